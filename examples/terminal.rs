@@ -1,12 +1,10 @@
 //! Output data written on a virtual serial port on stdout.
 
 use usb_device::prelude::*;
-use usbip_usbd::Server;
+use usbip_usbd::{DeviceRunner, Server};
 use usbd_serial::{USB_CLASS_CDC, SerialPort};
 use tokio::io::AsyncWriteExt as _;
-use tokio::time::delay_for;
 use std::process::Command;
-use std::time::Duration;
 //use tokio::sync::Mutex;
 
 #[tokio::main]
@@ -31,52 +29,50 @@ async fn main() {
         .spawn()
         .expect("failed to spawn usbip");
 
-    while let Ok(mut client) = listener.accept().await {
-        tokio::spawn(async move {
-            let mut serial = SerialPort::new();
+    let (usbcore, poller) = listener.attach("1-1")
+        .expect("Failed to attach device");
 
-            let (usbcore, mut _poller) = client.attach("1-1");
+    tokio::spawn(async move {
+        let mut serial = SerialPort::new();
 
-            let mut usb_dev = UsbDeviceBuilder::new(usbcore, UsbVidPid(0x16c0, 0x27dd))
-                .manufacturer("Fake company")
-                .product("USB-IP port")
-                .serial_number("TEST")
-                .device_class(USB_CLASS_CDC)
-                .build(&mut serial)
-                .expect("Building device failed");
+        let usb_dev = UsbDeviceBuilder::new(usbcore, UsbVidPid(0x16c0, 0x27dd))
+            .manufacturer("Fake company")
+            .product("USB-IP port")
+            .serial_number("TEST")
+            .device_class(USB_CLASS_CDC)
+            .build(&mut serial)
+            .expect("Building device failed");
 
-            let mut stdout = tokio::io::stdout();
+        let mut stdout = tokio::io::stdout();
 
-            tokio::spawn(client.run());
+        let mut runner = DeviceRunner::new(poller, (usb_dev, serial));
 
-            loop {
-                delay_for(Duration::from_millis(10)).await;
-
-                // TODO: figure out when we need to fire this
-                //poller.poll().await;
+        loop {
+            let (usb_dev, serial) = runner.next().await;
 
-                //println!("pollo");
-
-                if usb_dev.poll(&mut serial).is_err() {
-                    continue;
-                }
+            if usb_dev.poll(serial).is_err() {
+                continue;
+            }
 
-                let mut buf = [0u8; 1024];
+            let mut buf = [0u8; 1024];
 
-                loop {
-                    match serial.read(&mut buf[..]) {
-                        Ok(count) => {
-                            stdout.write_all(&buf[..count]).await.expect("failed to write to stdout");
-                            stdout.flush().await.expect("failed to flush stdout");
-                        },
-                        Err(UsbError::WouldBlock) => break,
-                        Err(err) => {
-                            println!("Read error: {:?}", err);
-                            break;
-                        }
+            loop {
+                match serial.read(&mut buf[..]) {
+                    Ok(count) => {
+                        stdout.write_all(&buf[..count]).await.expect("failed to write to stdout");
+                        stdout.flush().await.expect("failed to flush stdout");
+                    },
+                    Err(UsbError::WouldBlock) => break,
+                    Err(err) => {
+                        println!("Read error: {:?}", err);
+                        break;
                     }
                 }
             }
-        });
+        }
+    });
+
+    while let Ok(client) = listener.accept().await {
+        tokio::spawn(client.run());
     }
 }
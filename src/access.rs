@@ -0,0 +1,218 @@
+//! Control over which hosts may list and import which devices.
+//!
+//! Policies only apply to connections that have a socket address, that is, TCP connections
+//! accepted by a [`Server`](crate::Server). Connections over a Unix domain socket are limited by
+//! the permissions of the socket instead.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Decides which devices a host may see and import.
+pub trait AccessPolicy: Send + Sync {
+    /// Returns true if `peer` may see the device `bus_id` in the device list. Devices a peer may
+    /// not list can't be imported either. Defaults to [`can_import`](Self::can_import).
+    fn can_list(&self, peer: &SocketAddr, bus_id: &str) -> bool {
+        self.can_import(peer, bus_id)
+    }
+
+    /// Returns true if `peer` may import the device `bus_id`.
+    fn can_import(&self, peer: &SocketAddr, bus_id: &str) -> bool;
+
+    /// Returns the number of devices `peer` may have imported at the same time, counting the
+    /// imports of all connections from the same IP address, or `None` for no limit.
+    fn max_imports(&self, _peer: &SocketAddr) -> Option<usize> {
+        None
+    }
+}
+
+/// Lets hosts in the given networks list and import every device.
+#[derive(Clone, Default, Debug)]
+pub struct CidrAllowlist {
+    networks: Vec<(IpAddr, u8)>,
+    max_imports: Option<usize>,
+}
+
+impl CidrAllowlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows the hosts in the network `network`/`prefix_len`, such as `10.0.0.0`/`8`. IPv4
+    /// networks also match IPv4 hosts connecting to a dual-stack socket.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix_len` is longer than the address.
+    pub fn allow(&mut self, network: IpAddr, prefix_len: u8) {
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        assert!(prefix_len <= max_len, "prefix length {} is too long for {}", prefix_len, network);
+
+        self.networks.push((network, prefix_len));
+    }
+
+    /// Limits the number of devices each host may import at the same time.
+    pub fn set_max_imports(&mut self, max_imports: usize) {
+        self.max_imports = Some(max_imports);
+    }
+}
+
+impl AccessPolicy for CidrAllowlist {
+    fn can_import(&self, peer: &SocketAddr, _bus_id: &str) -> bool {
+        let ip = canonical_ip(peer.ip());
+
+        self.networks.iter().any(|&(network, prefix_len)| in_network(ip, network, prefix_len))
+    }
+
+    fn max_imports(&self, _peer: &SocketAddr) -> Option<usize> {
+        self.max_imports
+    }
+}
+
+/// Lets each device be listed and imported by a fixed set of hosts. Devices that are not in the
+/// map are not available to anyone.
+#[derive(Clone, Default, Debug)]
+pub struct PeerMap {
+    peers: HashMap<String, HashSet<IpAddr>>,
+    max_imports: Option<usize>,
+}
+
+impl PeerMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets the host with the address `peer` list and import the device `bus_id`.
+    pub fn allow(&mut self, bus_id: &str, peer: IpAddr) {
+        self.peers.entry(bus_id.to_owned()).or_default().insert(canonical_ip(peer));
+    }
+
+    /// Limits the number of devices each host may import at the same time.
+    pub fn set_max_imports(&mut self, max_imports: usize) {
+        self.max_imports = Some(max_imports);
+    }
+}
+
+impl AccessPolicy for PeerMap {
+    fn can_import(&self, peer: &SocketAddr, bus_id: &str) -> bool {
+        self.peers.get(bus_id)
+            .map(|peers| peers.contains(&canonical_ip(peer.ip())))
+            .unwrap_or(false)
+    }
+
+    fn max_imports(&self, _peer: &SocketAddr) -> Option<usize> {
+        self.max_imports
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, to plain IPv4 addresses.
+pub(crate) fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)))
+            },
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+
+            u32::from(ip) & mask == u32::from(network) & mask
+        },
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0);
+
+            u128::from(ip) & mask == u128::from(network) & mask
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 3240)
+    }
+
+    fn allowlist(network: &str, prefix_len: u8) -> CidrAllowlist {
+        let mut allowlist = CidrAllowlist::new();
+        allowlist.allow(network.parse().unwrap(), prefix_len);
+        allowlist
+    }
+
+    #[test]
+    fn zero_prefix_matches_everything() {
+        let v4 = allowlist("10.0.0.0", 0);
+
+        assert!(v4.can_import(&peer("10.0.0.1"), "1-1"));
+        assert!(v4.can_import(&peer("192.168.1.1"), "1-1"));
+        assert!(v4.can_import(&peer("255.255.255.255"), "1-1"));
+        assert!(!v4.can_import(&peer("2001:db8::1"), "1-1"));
+
+        let v6 = allowlist("::", 0);
+
+        assert!(v6.can_import(&peer("2001:db8::1"), "1-1"));
+        assert!(v6.can_import(&peer("ffff::1"), "1-1"));
+    }
+
+    #[test]
+    fn full_v4_prefix_matches_one_host() {
+        let allowlist = allowlist("10.0.0.1", 32);
+
+        assert!(allowlist.can_import(&peer("10.0.0.1"), "1-1"));
+        assert!(!allowlist.can_import(&peer("10.0.0.2"), "1-1"));
+        assert!(!allowlist.can_import(&peer("11.0.0.1"), "1-1"));
+    }
+
+    #[test]
+    fn full_v6_prefix_matches_one_host() {
+        let allowlist = allowlist("2001:db8::1", 128);
+
+        assert!(allowlist.can_import(&peer("2001:db8::1"), "1-1"));
+        assert!(!allowlist.can_import(&peer("2001:db8::2"), "1-1"));
+        assert!(!allowlist.can_import(&peer("3001:db8::1"), "1-1"));
+    }
+
+    #[test]
+    fn partial_prefix() {
+        let allowlist = allowlist("192.168.0.0", 16);
+
+        assert!(allowlist.can_import(&peer("192.168.255.1"), "1-1"));
+        assert!(!allowlist.can_import(&peer("192.169.0.1"), "1-1"));
+    }
+
+    #[test]
+    fn v4_mapped_peer_matches_v4_network() {
+        let allowlist = allowlist("10.0.0.0", 8);
+
+        assert!(allowlist.can_import(&peer("::ffff:10.1.2.3"), "1-1"));
+        assert!(!allowlist.can_import(&peer("::ffff:11.1.2.3"), "1-1"));
+    }
+
+    #[test]
+    fn peer_map_canonicalizes_addresses() {
+        let mut map = PeerMap::new();
+        map.allow("1-1", "::ffff:10.0.0.1".parse().unwrap());
+
+        assert!(map.can_import(&peer("10.0.0.1"), "1-1"));
+        assert!(map.can_import(&peer("::ffff:10.0.0.1"), "1-1"));
+        assert!(!map.can_import(&peer("10.0.0.1"), "1-2"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_long_prefix_panics() {
+        allowlist("10.0.0.0", 33);
+    }
+}
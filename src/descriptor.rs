@@ -0,0 +1,625 @@
+//! Typed model of the standard descriptors of a device.
+
+use std::collections::{BTreeMap, BTreeSet};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use usb_device::descriptor::descriptor_type;
+use crate::Error;
+
+/// All descriptors of a device.
+#[derive(Clone, Debug)]
+pub struct DeviceDescriptors {
+    pub device: DeviceDescriptor,
+    pub configurations: Vec<ConfigurationDescriptor>,
+
+    /// Language IDs from string descriptor zero. Empty if the device has no strings.
+    pub lang_ids: Vec<u16>,
+
+    /// String descriptors by language ID and string index.
+    pub strings: BTreeMap<u16, BTreeMap<u8, String>>,
+}
+
+impl DeviceDescriptors {
+    /// Returns string `index` in the first language listed by the device.
+    pub fn string(&self, index: u8) -> Option<&str> {
+        let lang_id = self.lang_ids.first()?;
+
+        self.strings.get(lang_id)?.get(&index).map(String::as_str)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub max_packet_size_0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub manufacturer_index: u8,
+    pub product_index: u8,
+    pub serial_number_index: u8,
+    pub num_configurations: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConfigurationDescriptor {
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    pub configuration_value: u8,
+    pub configuration_index: u8,
+    pub attributes: u8,
+    pub max_power: u8,
+    pub interfaces: Vec<Interface>,
+    pub associations: Vec<InterfaceAssociationDescriptor>,
+
+    /// Other descriptors that are not part of an interface.
+    pub extra: Vec<ClassDescriptor>,
+}
+
+/// An interface together with all of its alternate settings.
+#[derive(Clone, Debug)]
+pub struct Interface {
+    pub number: u8,
+    pub alt_settings: Vec<InterfaceDescriptor>,
+}
+
+#[derive(Clone, Debug)]
+pub struct InterfaceDescriptor {
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub num_endpoints: u8,
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+    pub interface_index: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+
+    /// Descriptors between the interface descriptor and its first endpoint, such as CDC
+    /// functional descriptors or the HID descriptor.
+    pub extra: Vec<ClassDescriptor>,
+}
+
+#[derive(Clone, Debug)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+
+    /// Descriptors following the endpoint descriptor.
+    pub extra: Vec<ClassDescriptor>,
+}
+
+#[derive(Clone, Debug)]
+pub struct InterfaceAssociationDescriptor {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub function_index: u8,
+}
+
+/// A class-specific or otherwise unknown descriptor.
+#[derive(Clone, Debug)]
+pub struct ClassDescriptor {
+    pub descriptor_type: u8,
+
+    /// Contents of the descriptor after the length and type fields.
+    pub data: Bytes,
+}
+
+/// A device that descriptors can be read from with GET_DESCRIPTOR requests.
+pub(crate) trait DescriptorSource {
+    /// Reads descriptor `index` of type `descriptor_type`. `lang_id` is only used for strings.
+    fn get_descriptor(&mut self, descriptor_type: u8, index: u8, lang_id: u16)
+        -> BoxFuture<'_, Result<Bytes, Error>>;
+}
+
+/// Reads and parses all descriptors of a device, including every string referenced by them in
+/// every language.
+pub(crate) async fn read_descriptors<D: DescriptorSource>(dev: &mut D) -> Result<DeviceDescriptors, Error> {
+    let device = parse_device(&dev.get_descriptor(descriptor_type::DEVICE, 0, 0).await?)?;
+
+    let mut configurations = Vec::new();
+    for index in 0..device.num_configurations {
+        let config = dev.get_descriptor(descriptor_type::CONFIGURATION, index, 0).await?;
+
+        configurations.push(parse_configuration(&config)?);
+    }
+
+    // Devices without strings stall the request for string descriptor zero
+    let lang_ids = match dev.get_descriptor(descriptor_type::STRING, 0, 0).await {
+        Ok(desc) => parse_lang_ids(&desc)?,
+        Err(Error::Transfer(_)) => Vec::new(),
+        Err(err) => return Err(err),
+    };
+
+    let indices = string_indices(&device, &configurations);
+
+    let mut strings = BTreeMap::new();
+
+    for &lang_id in &lang_ids {
+        let mut lang_strings = BTreeMap::new();
+
+        for &index in &indices {
+            match dev.get_descriptor(descriptor_type::STRING, index, lang_id).await {
+                Ok(desc) => {
+                    lang_strings.insert(index, parse_string(&desc)?);
+                },
+                // Missing strings are left out
+                Err(Error::Transfer(_)) => { },
+                Err(err) => return Err(err),
+            }
+        }
+
+        strings.insert(lang_id, lang_strings);
+    }
+
+    Ok(DeviceDescriptors {
+        device,
+        configurations,
+        lang_ids,
+        strings,
+    })
+}
+
+fn u16_le(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn check_header(buf: &[u8], dtype: u8, min_len: usize, name: &str) -> Result<(), Error> {
+    if buf.len() < min_len {
+        return Err(Error::Device(format!("invalid {} descriptor: data length too short", name)));
+    }
+
+    if usize::from(buf[0]) < min_len {
+        return Err(Error::Device(format!("invalid {} descriptor: length field too small", name)));
+    }
+
+    if buf[1] != dtype {
+        return Err(Error::Device(format!("invalid {} descriptor: incorrect descriptor type", name)));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn parse_device(buf: &[u8]) -> Result<DeviceDescriptor, Error> {
+    check_header(buf, descriptor_type::DEVICE, 18, "device")?;
+
+    Ok(DeviceDescriptor {
+        usb_version: u16_le(buf, 2),
+        device_class: buf[4],
+        device_subclass: buf[5],
+        device_protocol: buf[6],
+        max_packet_size_0: buf[7],
+        vendor_id: u16_le(buf, 8),
+        product_id: u16_le(buf, 10),
+        device_version: u16_le(buf, 12),
+        manufacturer_index: buf[14],
+        product_index: buf[15],
+        serial_number_index: buf[16],
+        num_configurations: buf[17],
+    })
+}
+
+/// Parses a configuration descriptor together with all the descriptors that follow it.
+pub(crate) fn parse_configuration(buf: &[u8]) -> Result<ConfigurationDescriptor, Error> {
+    check_header(buf, descriptor_type::CONFIGURATION, 9, "configuration")?;
+
+    let total_length = u16_le(buf, 2);
+
+    if usize::from(total_length) != buf.len() {
+        return Err(Error::Device("invalid configuration descriptor: wTotalLength mismatch".into()));
+    }
+
+    let mut config = ConfigurationDescriptor {
+        total_length,
+        num_interfaces: buf[4],
+        configuration_value: buf[5],
+        configuration_index: buf[6],
+        attributes: buf[7],
+        max_power: buf[8],
+        interfaces: Vec::new(),
+        associations: Vec::new(),
+        extra: Vec::new(),
+    };
+
+    // Indexes of the interface and alternate setting being parsed
+    let mut current: Option<(usize, usize)> = None;
+    let mut in_endpoint = false;
+
+    let mut pos = usize::from(buf[0]);
+
+    while pos < buf.len() {
+        if buf.len() - pos < 2 {
+            return Err(Error::Device("invalid configuration descriptor: truncated".into()));
+        }
+
+        let len = usize::from(buf[pos]);
+        let dtype = buf[pos + 1];
+
+        if len < 2 || len > buf.len() - pos {
+            return Err(Error::Device("invalid configuration descriptor: bad descriptor length".into()));
+        }
+
+        let desc = &buf[pos..pos + len];
+        pos += len;
+
+        match dtype {
+            descriptor_type::INTERFACE => {
+                if len < 9 {
+                    return Err(Error::Device("invalid interface descriptor: too short".into()));
+                }
+
+                let alt = InterfaceDescriptor {
+                    interface_number: desc[2],
+                    alternate_setting: desc[3],
+                    num_endpoints: desc[4],
+                    interface_class: desc[5],
+                    interface_subclass: desc[6],
+                    interface_protocol: desc[7],
+                    interface_index: desc[8],
+                    endpoints: Vec::new(),
+                    extra: Vec::new(),
+                };
+
+                let index = match config.interfaces.iter().position(|i| i.number == alt.interface_number) {
+                    Some(index) => index,
+                    None => {
+                        config.interfaces.push(Interface {
+                            number: alt.interface_number,
+                            alt_settings: Vec::new(),
+                        });
+
+                        config.interfaces.len() - 1
+                    },
+                };
+
+                let alt_settings = &mut config.interfaces[index].alt_settings;
+                alt_settings.push(alt);
+
+                current = Some((index, alt_settings.len() - 1));
+                in_endpoint = false;
+            },
+
+            descriptor_type::ENDPOINT => {
+                if len < 7 {
+                    return Err(Error::Device("invalid endpoint descriptor: too short".into()));
+                }
+
+                let (iface, alt) = current.ok_or_else(||
+                    Error::Device("invalid configuration descriptor: endpoint outside of an interface".into()))?;
+
+                config.interfaces[iface].alt_settings[alt].endpoints.push(EndpointDescriptor {
+                    address: desc[2],
+                    attributes: desc[3],
+                    max_packet_size: u16_le(desc, 4),
+                    interval: desc[6],
+                    extra: Vec::new(),
+                });
+
+                in_endpoint = true;
+            },
+
+            descriptor_type::IAD => {
+                if len < 8 {
+                    return Err(Error::Device("invalid interface association descriptor: too short".into()));
+                }
+
+                config.associations.push(InterfaceAssociationDescriptor {
+                    first_interface: desc[2],
+                    interface_count: desc[3],
+                    function_class: desc[4],
+                    function_subclass: desc[5],
+                    function_protocol: desc[6],
+                    function_index: desc[7],
+                });
+
+                // An association always precedes the interfaces it groups
+                current = None;
+                in_endpoint = false;
+            },
+
+            _ => {
+                let extra = ClassDescriptor {
+                    descriptor_type: dtype,
+                    data: Bytes::copy_from_slice(&desc[2..]),
+                };
+
+                // Attach to the closest preceding interface or endpoint
+                match current {
+                    Some((iface, alt)) => {
+                        let alt = &mut config.interfaces[iface].alt_settings[alt];
+
+                        match alt.endpoints.last_mut() {
+                            Some(ep) if in_endpoint => ep.extra.push(extra),
+                            _ => alt.extra.push(extra),
+                        }
+                    },
+                    None => config.extra.push(extra),
+                }
+            },
+        }
+    }
+
+    Ok(config)
+}
+
+fn parse_lang_ids(buf: &[u8]) -> Result<Vec<u16>, Error> {
+    check_header(buf, descriptor_type::STRING, 2, "string")?;
+
+    let len = usize::from(buf[0]).min(buf.len());
+
+    Ok(buf[2..len].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect())
+}
+
+fn parse_string(buf: &[u8]) -> Result<String, Error> {
+    check_header(buf, descriptor_type::STRING, 2, "string")?;
+
+    let len = usize::from(buf[0]).min(buf.len());
+
+    let chars = buf[2..len].chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<_>>();
+
+    Ok(String::from_utf16_lossy(&chars))
+}
+
+/// Returns the indexes of all strings referenced by the descriptors.
+fn string_indices(device: &DeviceDescriptor, configurations: &[ConfigurationDescriptor]) -> BTreeSet<u8> {
+    let mut indices = BTreeSet::new();
+
+    indices.insert(device.manufacturer_index);
+    indices.insert(device.product_index);
+    indices.insert(device.serial_number_index);
+
+    for config in configurations {
+        indices.insert(config.configuration_index);
+
+        for iad in &config.associations {
+            indices.insert(iad.function_index);
+        }
+
+        for iface in &config.interfaces {
+            for alt in &iface.alt_settings {
+                indices.insert(alt.interface_index);
+            }
+        }
+    }
+
+    // Zero means no string
+    indices.remove(&0);
+
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CS_INTERFACE: u8 = 0x24;
+    const CS_ENDPOINT: u8 = 0x25;
+
+    /// Builds a configuration descriptor with a correct wTotalLength out of the descriptors that
+    /// follow it.
+    fn config(num_interfaces: u8, descriptors: &[&[u8]]) -> Vec<u8> {
+        let mut buf = vec![9, descriptor_type::CONFIGURATION, 0, 0, num_interfaces, 1, 0, 0x80, 50];
+
+        for desc in descriptors {
+            buf.extend_from_slice(desc);
+        }
+
+        let total_length = buf.len() as u16;
+        buf[2..4].copy_from_slice(&total_length.to_le_bytes());
+
+        buf
+    }
+
+    fn expect_error<T: std::fmt::Debug>(res: Result<T, Error>, what: &str) {
+        match res {
+            Err(Error::Device(msg)) => assert!(msg.contains(what), "unexpected message: {}", msg),
+            res => panic!("expected a device error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn alt_settings_are_grouped() {
+        let buf = config(2, &[
+            &[9, descriptor_type::INTERFACE, 0, 0, 0, 0xff, 0, 0, 0],
+            &[9, descriptor_type::INTERFACE, 1, 0, 0, 0xff, 0, 0, 0],
+            &[9, descriptor_type::INTERFACE, 0, 1, 1, 0xff, 0, 0, 0],
+            &[7, descriptor_type::ENDPOINT, 0x81, 0x01, 0xff, 0x03, 1],
+        ]);
+
+        let config = parse_configuration(&buf).unwrap();
+
+        assert_eq!(config.total_length as usize, buf.len());
+        assert_eq!(config.num_interfaces, 2);
+        assert_eq!(config.interfaces.len(), 2);
+
+        let iface = &config.interfaces[0];
+        assert_eq!(iface.number, 0);
+        assert_eq!(iface.alt_settings.len(), 2);
+        assert_eq!(iface.alt_settings[0].alternate_setting, 0);
+        assert!(iface.alt_settings[0].endpoints.is_empty());
+        assert_eq!(iface.alt_settings[1].alternate_setting, 1);
+
+        let ep = &iface.alt_settings[1].endpoints[0];
+        assert_eq!(ep.address, 0x81);
+        assert_eq!(ep.attributes, 0x01);
+        assert_eq!(ep.max_packet_size, 1023);
+        assert_eq!(ep.interval, 1);
+
+        assert_eq!(config.interfaces[1].number, 1);
+        assert_eq!(config.interfaces[1].alt_settings.len(), 1);
+    }
+
+    #[test]
+    fn interface_associations() {
+        let buf = config(3, &[
+            &[9, descriptor_type::INTERFACE, 0, 0, 0, 0xff, 0, 0, 0],
+            &[8, descriptor_type::IAD, 1, 2, 0x01, 0x02, 0x03, 4],
+            &[9, descriptor_type::INTERFACE, 1, 0, 0, 0x01, 0x01, 0, 0],
+            &[9, descriptor_type::INTERFACE, 2, 0, 0, 0x01, 0x02, 0, 0],
+        ]);
+
+        let config = parse_configuration(&buf).unwrap();
+
+        assert_eq!(config.interfaces.len(), 3);
+        assert_eq!(config.associations.len(), 1);
+
+        let iad = &config.associations[0];
+        assert_eq!(iad.first_interface, 1);
+        assert_eq!(iad.interface_count, 2);
+        assert_eq!(iad.function_class, 0x01);
+        assert_eq!(iad.function_subclass, 0x02);
+        assert_eq!(iad.function_protocol, 0x03);
+        assert_eq!(iad.function_index, 4);
+
+        // The association doesn't belong to the interface before it
+        assert!(config.interfaces[0].alt_settings[0].extra.is_empty());
+        assert!(config.extra.is_empty());
+    }
+
+    #[test]
+    fn class_descriptors_attach_to_interface_and_endpoint() {
+        // CDC ACM, as written by usbd-serial, with a made up class-specific endpoint descriptor
+        let buf = config(2, &[
+            &[8, descriptor_type::IAD, 0, 2, 0x02, 0x02, 0x00, 0],
+            &[9, descriptor_type::INTERFACE, 0, 0, 1, 0x02, 0x02, 0x00, 0],
+            &[5, CS_INTERFACE, 0x00, 0x10, 0x01], // header
+            &[5, CS_INTERFACE, 0x01, 0x00, 0x01], // call management
+            &[4, CS_INTERFACE, 0x02, 0x00], // ACM
+            &[5, CS_INTERFACE, 0x06, 0x00, 0x01], // union
+            &[7, descriptor_type::ENDPOINT, 0x81, 0x03, 8, 0, 255],
+            &[3, CS_ENDPOINT, 0x01],
+            &[9, descriptor_type::INTERFACE, 1, 0, 2, 0x0a, 0x00, 0x00, 0],
+            &[7, descriptor_type::ENDPOINT, 0x02, 0x02, 64, 0, 0],
+            &[7, descriptor_type::ENDPOINT, 0x82, 0x02, 64, 0, 0],
+        ]);
+
+        let config = parse_configuration(&buf).unwrap();
+
+        assert_eq!(config.associations.len(), 1);
+        assert!(config.extra.is_empty());
+
+        let comm = &config.interfaces[0].alt_settings[0];
+
+        let functional = comm.extra.iter()
+            .map(|d| (d.descriptor_type, d.data[0]))
+            .collect::<Vec<_>>();
+
+        assert_eq!(functional, [
+            (CS_INTERFACE, 0x00),
+            (CS_INTERFACE, 0x01),
+            (CS_INTERFACE, 0x02),
+            (CS_INTERFACE, 0x06),
+        ]);
+        assert_eq!(&comm.extra[3].data[..], &[0x06, 0x00, 0x01]);
+
+        assert_eq!(comm.endpoints.len(), 1);
+        assert_eq!(comm.endpoints[0].extra.len(), 1);
+        assert_eq!(comm.endpoints[0].extra[0].descriptor_type, CS_ENDPOINT);
+
+        let data = &config.interfaces[1].alt_settings[0];
+
+        assert!(data.extra.is_empty());
+        assert_eq!(data.endpoints.iter().map(|ep| ep.address).collect::<Vec<_>>(), [0x02, 0x82]);
+        assert!(data.endpoints.iter().all(|ep| ep.extra.is_empty()));
+    }
+
+    #[test]
+    fn descriptors_before_interfaces_belong_to_configuration() {
+        let buf = config(1, &[
+            &[4, 0x21, 0xaa, 0xbb],
+            &[9, descriptor_type::INTERFACE, 0, 0, 0, 0xff, 0, 0, 0],
+        ]);
+
+        let config = parse_configuration(&buf).unwrap();
+
+        assert_eq!(config.extra.len(), 1);
+        assert_eq!(&config.extra[0].data[..], &[0xaa, 0xbb]);
+        assert!(config.interfaces[0].alt_settings[0].extra.is_empty());
+    }
+
+    #[test]
+    fn configuration_too_short() {
+        expect_error(parse_configuration(&[9, descriptor_type::CONFIGURATION, 9, 0]), "data length too short");
+    }
+
+    #[test]
+    fn configuration_truncated() {
+        // A single byte can't even hold a descriptor header
+        let buf = config(0, &[&[9]]);
+
+        expect_error(parse_configuration(&buf), "truncated");
+
+        // The last descriptor claims to be longer than what is left
+        let buf = config(1, &[&[9, descriptor_type::INTERFACE, 0, 0, 0]]);
+
+        expect_error(parse_configuration(&buf), "bad descriptor length");
+    }
+
+    #[test]
+    fn configuration_bad_descriptor_length() {
+        for &len in &[0, 1] {
+            let buf = config(1, &[&[len, descriptor_type::INTERFACE, 0, 0]]);
+
+            expect_error(parse_configuration(&buf), "bad descriptor length");
+        }
+    }
+
+    #[test]
+    fn configuration_total_length_mismatch() {
+        let mut buf = config(1, &[&[9, descriptor_type::INTERFACE, 0, 0, 0, 0xff, 0, 0, 0]]);
+
+        buf[2] += 1;
+        expect_error(parse_configuration(&buf), "wTotalLength mismatch");
+
+        buf[2] -= 2;
+        expect_error(parse_configuration(&buf), "wTotalLength mismatch");
+    }
+
+    #[test]
+    fn endpoint_outside_interface() {
+        let buf = config(0, &[&[7, descriptor_type::ENDPOINT, 0x81, 0x03, 8, 0, 1]]);
+
+        expect_error(parse_configuration(&buf), "endpoint outside of an interface");
+    }
+
+    #[test]
+    fn strings() {
+        let buf = [10, descriptor_type::STRING, b'E', 0, b'c', 0, b'h', 0, b'o', 0];
+
+        assert_eq!(parse_string(&buf).unwrap(), "Echo");
+
+        // Anything past bLength is ignored
+        let mut longer = buf.to_vec();
+        longer[0] = 6;
+        assert_eq!(parse_string(&longer).unwrap(), "Ec");
+
+        assert_eq!(parse_string(&[2, descriptor_type::STRING]).unwrap(), "");
+
+        expect_error(parse_string(&[4, descriptor_type::DEVICE, b'E', 0]), "incorrect descriptor type");
+        expect_error(parse_string(&[1, descriptor_type::STRING]), "length field too small");
+        expect_error(parse_string(&[4]), "data length too short");
+    }
+
+    #[test]
+    fn lang_ids() {
+        let buf = [6, descriptor_type::STRING, 0x09, 0x04, 0x07, 0x04];
+
+        assert_eq!(parse_lang_ids(&buf).unwrap(), [0x0409, 0x0407]);
+
+        // A bLength longer than the data is clamped
+        let mut longer = buf;
+        longer[0] = 8;
+        assert_eq!(parse_lang_ids(&longer).unwrap(), [0x0409, 0x0407]);
+
+        expect_error(parse_lang_ids(&[0, descriptor_type::STRING]), "length field too small");
+    }
+}
@@ -3,6 +3,7 @@ use usb_device::{
     usbcore,
     endpoint::{EndpointAddress, EndpointConfig, OutPacketType},
 };
+use crate::protocol::{ResponseStatus, URB_SHORT_NOT_OK, URB_ZERO_PACKET};
 use crate::server::{ControlState, CoreChannel, Urb};
 
 fn update_urb<'a>(
@@ -10,6 +11,18 @@ fn update_urb<'a>(
     urb: &'a mut Option<Urb>,
     channel: &mut CoreChannel) -> Option<&'a mut Urb>
 {
+    if urb.as_ref().map(|u| !channel.is_live(u)).unwrap_or(false) {
+        // The URB was unlinked or the host that submitted it has disconnected
+        channel.discard_urb(urb.take().unwrap());
+    }
+
+    if channel.is_stalled(ep_addr) {
+        // The endpoint was halted while a transfer was in progress
+        if let Some(urb) = urb.take() {
+            channel.stall_urb(urb);
+        }
+    }
+
     if urb.is_none() {
         *urb = channel.take_next_urb(ep_addr);
     }
@@ -21,7 +34,6 @@ pub struct EndpointOut {
     address: EndpointAddress,
     max_packet_size: usize,
     channel: CoreChannel,
-    stalled: bool,
     urb: Option<Urb>,
 }
 
@@ -31,7 +43,6 @@ impl EndpointOut {
             address,
             max_packet_size,
             channel,
-            stalled: false,
             urb: None,
         }
     }
@@ -53,19 +64,25 @@ impl usbcore::UsbEndpoint for EndpointOut {
     }
 
     fn set_stalled(&mut self, is_stalled: bool) -> Result<()> {
-        //println!("{:?} set_stalled({})", self.address, is_stalled);
-        self.stalled = is_stalled;
+        tracing::debug!(ep = ?self.address, stalled = is_stalled, "set_stalled");
+        self.channel.set_stalled(self.address, is_stalled);
+
+        if is_stalled {
+            if let Some(urb) = self.urb.take() {
+                self.channel.stall_urb(urb);
+            }
+        }
 
         Ok(())
     }
 
     fn is_stalled(&mut self) -> Result<bool> {
-        Ok(self.stalled)
+        Ok(self.channel.is_stalled(self.address))
     }
 }
 
-impl usbcore::UsbEndpointOut for EndpointOut {
-    fn read_packet(&mut self, buf: &mut [u8]) -> Result<(usize, OutPacketType)> {
+impl EndpointOut {
+    fn read_urb_packet(&mut self, buf: &mut [u8]) -> Result<(usize, OutPacketType)> {
         if buf.len() < self.max_packet_size {
             return Err(UsbError::BufferOverflow);
         }
@@ -73,8 +90,6 @@ impl usbcore::UsbEndpointOut for EndpointOut {
         let urb = update_urb(self.address, &mut self.urb, &mut self.channel)
             .ok_or(UsbError::WouldBlock)?;
 
-        //println!("read {:?}", self.address);
-
         if let Some(ref mut control) = urb.control {
             match control.state {
                 ControlState::Setup => {
@@ -115,32 +130,64 @@ impl usbcore::UsbEndpointOut for EndpointOut {
                     return Ok((0, OutPacketType::Data));
                 },
 
-                ControlState::Complete => panic!("Complete control USB passed to OUT endpoint"),
+                ControlState::Complete => {
+                    // Complete control URBs are never queued
+                    self.channel.fail_urb(self.urb.take().unwrap(), ResponseStatus::ProtocolError);
+
+                    return Err(UsbError::InvalidState);
+                },
             }
         }
 
-        //println!("data: {:?}", urb.data);
+        if let Some(ref mut iso) = urb.iso {
+            // Each isochronous packet is read as a single packet, however long it is
+
+            let packet = &mut iso.packets[iso.next_packet];
+            let offset = packet.offset as usize;
+            let len = (packet.length as usize).min(buf.len());
+
+            buf[..len].copy_from_slice(&urb.data[offset..offset + len]);
+
+            packet.actual_length = len as u32;
+            packet.status = if len < packet.length as usize {
+                // The rest of the packet didn't fit in the buffer and was dropped
+                ResponseStatus::Overflow.code()
+            } else {
+                ResponseStatus::Ok.code()
+            };
+
+            iso.next_packet += 1;
+
+            if iso.next_packet == iso.packets.len() {
+                urb.data.clear();
+
+                self.channel.complete_urb(self.urb.take().unwrap());
+            }
+
+            return Ok((len, OutPacketType::Data));
+        }
 
         if urb.data.len() <= self.max_packet_size {
             // The remaining data will be returned by this read, so the URB will be completed
 
-            // TODO: Do we need to simulate ZLP
-
             let len = urb.data.len();
             buf[..len].copy_from_slice(urb.data.split_to(len).as_ref());
 
-            if let Some(ref mut control) = urb.control {
-                match control.state {
-                    ControlState::Setup => panic!("Invalid read in Setup state"),
+            if len == self.max_packet_size
+                && urb.control.is_none()
+                && urb.transfer_flags & URB_ZERO_PACKET != 0
+            {
+                // The host wants the transfer terminated with a ZLP, which the next read will
+                // return before the URB is completed
 
-                    ControlState::Data => {
-                        control.state = ControlState::Status;
-                    },
+                urb.transfer_flags &= !URB_ZERO_PACKET;
 
-                    ControlState::Status => panic!("Invalid read in Status state"),
+                return Ok((len, OutPacketType::Data));
+            }
 
-                    ControlState::Complete => panic!("Complete control USB passed to OUT endpoint"),
-                }
+            if let Some(ref mut control) = urb.control {
+                // Only the Data state gets this far
+                control.state = ControlState::Status;
             }
 
             self.channel.complete_urb(self.urb.take().unwrap());
@@ -150,18 +197,28 @@ impl usbcore::UsbEndpointOut for EndpointOut {
             // A single packet will be read
 
             let len = self.max_packet_size;
-            buf.copy_from_slice(urb.data.split_to(len).as_ref());
+            buf[..len].copy_from_slice(urb.data.split_to(len).as_ref());
 
             Ok((len, OutPacketType::Data))
         }
     }
 }
 
+impl usbcore::UsbEndpointOut for EndpointOut {
+    fn read_packet(&mut self, buf: &mut [u8]) -> Result<(usize, OutPacketType)> {
+        let res = self.read_urb_packet(buf);
+
+        // Keep reporting the endpoint as readable while there is data left in the current URB
+        self.channel.set_out_pending(self.address, self.urb.is_some());
+
+        res
+    }
+}
+
 pub struct EndpointIn {
     address: EndpointAddress,
     max_packet_size: usize,
     channel: CoreChannel,
-    stalled: bool,
     urb: Option<Urb>,
 }
 
@@ -171,7 +228,6 @@ impl EndpointIn {
             address,
             max_packet_size,
             channel,
-            stalled: false,
             urb: None,
         }
     }
@@ -193,14 +249,20 @@ impl usbcore::UsbEndpoint for EndpointIn {
     }
 
     fn set_stalled(&mut self, is_stalled: bool) -> Result<()> {
-        //println!("{:?} set_stalled({})", self.address, is_stalled);
-        self.stalled = is_stalled;
+        tracing::debug!(ep = ?self.address, stalled = is_stalled, "set_stalled");
+        self.channel.set_stalled(self.address, is_stalled);
+
+        if is_stalled {
+            if let Some(urb) = self.urb.take() {
+                self.channel.stall_urb(urb);
+            }
+        }
 
         Ok(())
     }
 
     fn is_stalled(&mut self) -> Result<bool> {
-        Ok(self.stalled)
+        Ok(self.channel.is_stalled(self.address))
     }
 }
 
@@ -210,21 +272,64 @@ impl usbcore::UsbEndpointIn for EndpointIn {
             return Err(UsbError::BufferOverflow);
         }
 
-        //println!("writing {:?} {}", buf, self.max_packet_size);
+        let urb = match update_urb(self.address, &mut self.urb, &mut self.channel) {
+            Some(urb) => urb,
+            None => {
+                self.channel.set_in_waiting(self.address);
+                return Err(UsbError::WouldBlock);
+            },
+        };
 
-        let urb = update_urb(self.address, &mut self.urb, &mut self.channel)
-            .ok_or(UsbError::WouldBlock)?;
+        if let Some(ref mut iso) = urb.iso {
+            // Each write fills one isochronous packet. IN data is packed back to back.
+
+            let packet = &mut iso.packets[iso.next_packet];
+            let len = buf.len().min(packet.length as usize);
+
+            urb.data.extend_from_slice(&buf[..len]);
+
+            packet.actual_length = len as u32;
+            packet.status = if len < buf.len() {
+                ResponseStatus::Overflow.code()
+            } else {
+                ResponseStatus::Ok.code()
+            };
+
+            iso.next_packet += 1;
+
+            if iso.next_packet == iso.packets.len() {
+                self.channel.complete_urb(self.urb.take().unwrap());
+            }
+
+            self.channel.set_in_complete(self.address);
+
+            return Ok(());
+        }
+
+        // Never return more than the host asked for. The status stage of a control transfer
+        // carries no data.
+        let remaining = match urb.control {
+            Some(ref control) if control.state == ControlState::Status => 0,
+            _ => urb.len.saturating_sub(urb.data.len()),
+        };
+
+        let len = buf.len().min(remaining);
 
         // Add the buffer to the URB
-        urb.data.extend_from_slice(buf);
+        urb.data.extend_from_slice(&buf[..len]);
 
-        if buf.len() < self.max_packet_size {
+        if len < buf.len() {
+            // Babble - the device wrote more than fits in the host buffer
+            urb.span.in_scope(|| tracing::debug!(ep = ?self.address, written = buf.len(), remaining, "overflow"));
+
+            urb.status = ResponseStatus::Overflow;
+        }
+
+        if buf.len() < self.max_packet_size || len == remaining {
             // The URB is complete
 
             if let Some(ref mut control) = urb.control {
                 match control.state {
-                    ControlState::Setup => panic!("SETUP passed to IN endpoint"),
-
                     ControlState::Data => {
                         control.state = ControlState::Status;
                     }
@@ -233,13 +338,22 @@ impl usbcore::UsbEndpointIn for EndpointIn {
                         control.state = ControlState::Complete;
                     },
 
-                    ControlState::Complete => panic!("Complete control USB passed to IN endpoint"),
+                    ControlState::Setup | ControlState::Complete => {
+                        // SETUP and complete control URBs are never passed to IN endpoints
+                        self.channel.fail_urb(self.urb.take().unwrap(), ResponseStatus::ProtocolError);
+
+                        return Err(UsbError::InvalidState);
+                    },
                 }
+            } else if urb.data.len() < urb.len && urb.transfer_flags & URB_SHORT_NOT_OK != 0 {
+                urb.status = ResponseStatus::ShortTransfer;
             }
 
             self.channel.complete_urb(self.urb.take().unwrap());
         }
 
+        self.channel.set_in_complete(self.address);
+
         Ok(())
     }
 
@@ -0,0 +1,44 @@
+use std::{error, fmt, io};
+
+/// Errors returned by the server.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying connection or listener failed.
+    Io(io::Error),
+
+    /// The peer violated the USB/IP protocol. The connection cannot be used after this.
+    Protocol(String),
+
+    /// A virtual device could not be attached or failed to respond to enumeration.
+    Device(String),
+
+    /// A transfer to a device failed. The status is a negated Linux errno value, such as -32
+    /// (EPIPE) for a stalled endpoint.
+    Transfer(i32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Error::Device(msg) => write!(f, "device error: {}", msg),
+            Error::Transfer(status) => write!(f, "transfer failed with status {}", status),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,60 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use futures::future;
+use tokio::io::AsyncWriteExt as _;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs, UnixStream};
+use tracing::{Instrument as _, debug, info, warn};
+use crate::Error;
+
+/// Accepts TCP connections on `addr` and forwards each one to a new connection to the Unix domain
+/// socket at `path`, such as one created with [`Server::bind_unix`](crate::Server::bind_unix).
+/// This lets `usbip attach`, which only speaks TCP, reach a server on a Unix domain socket.
+///
+/// Anyone who can connect to `addr` can reach the server, so bind it to a loopback address and
+/// only run the forwarder for as long as it is needed. Runs until accepting a connection fails.
+pub async fn forward_unix<A: ToSocketAddrs>(addr: A, path: impl AsRef<Path>) -> Result<(), Error> {
+    let mut listener = TcpListener::bind(addr).await?;
+    let path = path.as_ref().to_owned();
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+
+        let span = tracing::info_span!("forward", %peer, path = %path.display());
+
+        tokio::spawn(forward_connection(stream, path.clone()).instrument(span));
+    }
+}
+
+async fn forward_connection(tcp: TcpStream, path: PathBuf) {
+    info!("forwarding");
+
+    match connect_and_copy(tcp, &path).await {
+        Ok(()) => debug!("closed"),
+        Err(err) => warn!(%err, "forwarding failed"),
+    }
+}
+
+async fn connect_and_copy(mut tcp: TcpStream, path: &Path) -> io::Result<()> {
+    let mut unix = UnixStream::connect(path).await?;
+
+    // USB/IP sends lots of small PDUs
+    tcp.set_nodelay(true)?;
+
+    let (mut tcp_read, mut tcp_write) = tcp.split();
+    let (mut unix_read, mut unix_write) = unix.split();
+
+    // Pass on the end of the stream in each direction so that both sides see the close
+    let to_unix = async {
+        tokio::io::copy(&mut tcp_read, &mut unix_write).await?;
+        unix_write.shutdown().await
+    };
+
+    let to_tcp = async {
+        tokio::io::copy(&mut unix_read, &mut tcp_write).await?;
+        tcp_write.shutdown().await
+    };
+
+    future::try_join(to_unix, to_tcp).await?;
+
+    Ok(())
+}
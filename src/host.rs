@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt as _};
+use futures::sink::SinkExt as _;
+use futures::stream::StreamExt as _;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::Framed;
+use usb_device::UsbDirection;
+use usb_device::control::Request as ControlRequest;
+use usb_device::endpoint::EndpointAddress;
+use crate::Error;
+use crate::descriptor::{self, DescriptorSource, DeviceDescriptors};
+use crate::protocol::*;
+#[cfg(feature = "tls")]
+use crate::tls::{rustls, webpki};
+
+/// A connection to a USB/IP server, seen from the host side.
+///
+/// The Linux `usbipd` closes the connection after answering a device list request, so when
+/// talking to it, use a new connection for each operation.
+pub struct UsbIpHost<S = TcpStream> {
+    framed: Framed<S, HostCodec>,
+}
+
+impl UsbIpHost<TcpStream> {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        Ok(Self::new(TcpStream::connect(addr).await?))
+    }
+}
+
+#[cfg(feature = "tls")]
+impl UsbIpHost<tokio_rustls::client::TlsStream<TcpStream>> {
+    /// Connects to a server that requires TLS. `config` should contain the client certificate
+    /// that the server knows the host by, and `domain` is the name the server certificate is
+    /// checked against.
+    pub async fn connect_tls<A: ToSocketAddrs>(addr: A, config: Arc<rustls::ClientConfig>, domain: &str)
+        -> Result<Self, Error>
+    {
+        let domain = webpki::DNSNameRef::try_from_ascii_str(domain)
+            .map_err(|_| Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "invalid domain name")))?;
+
+        let stream = TcpStream::connect(addr).await?;
+
+        let stream = tokio_rustls::TlsConnector::from(config).connect(domain, stream).await?;
+
+        Ok(Self::new(stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> UsbIpHost<S> {
+    /// Uses an already established connection to a server.
+    pub fn new(stream: S) -> Self {
+        UsbIpHost {
+            framed: Framed::new(stream, HostCodec::new()),
+        }
+    }
+
+    /// Lists the devices exported by the server.
+    pub async fn list_devices(&mut self) -> Result<Vec<Arc<DeviceInterfaceInfo>>, Error> {
+        self.framed.send(Request::DevList).await?;
+
+        match next_response(&mut self.framed).await? {
+            Response::DevList(devices) => Ok(devices),
+            res => Err(unexpected(&res)),
+        }
+    }
+
+    /// Imports the device with the bus ID `bus_id`. After this the connection is only used for
+    /// transfers to the device.
+    pub async fn import(mut self, bus_id: &str) -> Result<ImportedDevice<S>, Error> {
+        self.framed.send(Request::Import(bus_id.to_owned())).await?;
+
+        match next_response(&mut self.framed).await? {
+            Response::Import(ImportResponse { status: OpStatus::Ok, device: Some(info) }) => {
+                Ok(ImportedDevice {
+                    framed: self.framed,
+                    devid: (info.busnum << 16) | info.devnum,
+                    info,
+                    next_seqnum: 1,
+                    completed: HashMap::new(),
+                    unlinked: HashMap::new(),
+                })
+            },
+            Response::Import(res) => {
+                Err(Error::Device(format!("failed to import {}: {:?}", bus_id, res.status)))
+            },
+            res => Err(unexpected(&res)),
+        }
+    }
+}
+
+/// Result of a completed URB.
+#[derive(Debug)]
+pub struct Completion {
+    /// Status of the transfer as a negated Linux errno value. Zero means success.
+    pub status: i32,
+
+    /// Number of bytes transferred.
+    pub actual_length: usize,
+
+    /// Data returned by an IN transfer.
+    pub data: Bytes,
+}
+
+/// A device imported from a USB/IP server.
+///
+/// The `*_in`, `*_out` and `control_*` methods submit a single URB and wait for it to complete.
+/// For more control, for instance to cancel transfers, use [`submit_in`](Self::submit_in),
+/// [`submit_out`](Self::submit_out), [`wait`](Self::wait) and [`unlink`](Self::unlink).
+pub struct ImportedDevice<S = TcpStream> {
+    framed: Framed<S, HostCodec>,
+    info: Arc<DeviceInfo>,
+    devid: u32,
+    next_seqnum: u32,
+    // Responses received while waiting for something else
+    completed: HashMap<u32, SubmitResponse>,
+    unlinked: HashMap<u32, UnlinkResponse>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ImportedDevice<S> {
+    /// Returns the device information sent by the server when the device was imported.
+    pub fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    fn next_seqnum(&mut self) -> u32 {
+        let seqnum = self.next_seqnum;
+        self.next_seqnum = self.next_seqnum.wrapping_add(1).max(1);
+        seqnum
+    }
+
+    async fn submit(&mut self, ep: EndpointAddress, setup: Option<[u8; 8]>, data: &[u8], len: usize)
+        -> Result<u32, Error>
+    {
+        let seqnum = self.next_seqnum();
+
+        let transfer_flags = match ep.direction() {
+            UsbDirection::In => URB_DIR_IN,
+            UsbDirection::Out => 0,
+        };
+
+        self.framed.send(Request::Submit(SubmitRequest {
+            seqnum,
+            devid: self.devid,
+            ep,
+            transfer_flags,
+            transfer_buffer_length: len as u32,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup,
+            data: data.into(),
+            iso_packets: Vec::new(),
+        })).await?;
+
+        Ok(seqnum)
+    }
+
+    /// Submits a bulk or interrupt IN URB reading at most `len` bytes from endpoint `ep` and
+    /// returns its sequence number.
+    pub async fn submit_in(&mut self, ep: u8, len: usize) -> Result<u32, Error> {
+        self.submit(EndpointAddress::from_parts(ep, UsbDirection::In), None, &[], len).await
+    }
+
+    /// Submits a bulk or interrupt OUT URB writing `data` to endpoint `ep` and returns its
+    /// sequence number.
+    pub async fn submit_out(&mut self, ep: u8, data: &[u8]) -> Result<u32, Error> {
+        self.submit(EndpointAddress::from_parts(ep, UsbDirection::Out), None, data, data.len()).await
+    }
+
+    /// Waits for the URB with the sequence number `seqnum` to complete. Never returns for URBs
+    /// that were unlinked.
+    pub async fn wait(&mut self, seqnum: u32) -> Result<Completion, Error> {
+        loop {
+            if let Some(res) = self.completed.remove(&seqnum) {
+                return Ok(Completion {
+                    status: res.status,
+                    actual_length: res.actual_length as usize,
+                    data: res.data.freeze(),
+                });
+            }
+
+            self.receive().await?;
+        }
+    }
+
+    /// Cancels the URB with the sequence number `seqnum`. Returns `true` if it was cancelled, or
+    /// `false` if it had already completed, in which case its result is available from
+    /// [`wait`](Self::wait).
+    pub async fn unlink(&mut self, seqnum: u32) -> Result<bool, Error> {
+        let unlink_seqnum = seqnum;
+        let seqnum = self.next_seqnum();
+
+        self.framed.send(Request::Unlink(UnlinkRequest {
+            seqnum,
+            devid: self.devid,
+            // The endpoint is not used by servers
+            ep: EndpointAddress::from_parts(0, UsbDirection::Out),
+            unlink_seqnum,
+        })).await?;
+
+        loop {
+            if let Some(res) = self.unlinked.remove(&seqnum) {
+                return Ok(res.status == ResponseStatus::Unlinked.code());
+            }
+
+            self.receive().await?;
+        }
+    }
+
+    async fn receive(&mut self) -> Result<(), Error> {
+        match next_response(&mut self.framed).await? {
+            Response::Submit(res) => {
+                self.completed.insert(res.seqnum, res);
+            },
+            Response::Unlink(res) => {
+                self.unlinked.insert(res.seqnum, res);
+            },
+            res => return Err(unexpected(&res)),
+        }
+
+        Ok(())
+    }
+
+    async fn transfer(&mut self, ep: EndpointAddress, setup: Option<[u8; 8]>, data: &[u8], len: usize)
+        -> Result<Completion, Error>
+    {
+        let seqnum = self.submit(ep, setup, data, len).await?;
+
+        let completion = self.wait(seqnum).await?;
+
+        if completion.status != 0 {
+            return Err(Error::Transfer(completion.status));
+        }
+
+        Ok(completion)
+    }
+
+    /// Performs a control IN transfer on endpoint 0 and returns the data read.
+    pub async fn control_in(&mut self, request_type: u8, request: u8, value: u16, index: u16, length: u16)
+        -> Result<Bytes, Error>
+    {
+        let setup = setup_packet(request_type, request, value, index, length);
+        let ep = EndpointAddress::from_parts(0, UsbDirection::In);
+
+        Ok(self.transfer(ep, Some(setup), &[], length as usize).await?.data)
+    }
+
+    /// Performs a control OUT transfer on endpoint 0 and returns the number of bytes written.
+    pub async fn control_out(&mut self, request_type: u8, request: u8, value: u16, index: u16, data: &[u8])
+        -> Result<usize, Error>
+    {
+        let setup = setup_packet(request_type, request, value, index, data.len() as u16);
+        let ep = EndpointAddress::from_parts(0, UsbDirection::Out);
+
+        Ok(self.transfer(ep, Some(setup), data, data.len()).await?.actual_length)
+    }
+
+    /// Reads at most `len` bytes from bulk endpoint `ep`.
+    pub async fn bulk_in(&mut self, ep: u8, len: usize) -> Result<Bytes, Error> {
+        let ep = EndpointAddress::from_parts(ep, UsbDirection::In);
+
+        Ok(self.transfer(ep, None, &[], len).await?.data)
+    }
+
+    /// Writes `data` to bulk endpoint `ep` and returns the number of bytes written.
+    pub async fn bulk_out(&mut self, ep: u8, data: &[u8]) -> Result<usize, Error> {
+        let ep = EndpointAddress::from_parts(ep, UsbDirection::Out);
+
+        Ok(self.transfer(ep, None, data, data.len()).await?.actual_length)
+    }
+
+    /// Reads at most `len` bytes from interrupt endpoint `ep`.
+    pub async fn interrupt_in(&mut self, ep: u8, len: usize) -> Result<Bytes, Error> {
+        // Interrupt and bulk URBs look the same on the wire
+        self.bulk_in(ep, len).await
+    }
+
+    /// Writes `data` to interrupt endpoint `ep` and returns the number of bytes written.
+    pub async fn interrupt_out(&mut self, ep: u8, data: &[u8]) -> Result<usize, Error> {
+        self.bulk_out(ep, data).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> ImportedDevice<S> {
+    /// Reads all descriptors of the device.
+    pub async fn descriptors(&mut self) -> Result<DeviceDescriptors, Error> {
+        descriptor::read_descriptors(self).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> DescriptorSource for ImportedDevice<S> {
+    fn get_descriptor(&mut self, descriptor_type: u8, index: u8, lang_id: u16)
+        -> BoxFuture<'_, Result<Bytes, Error>>
+    {
+        let value = (u16::from(descriptor_type) << 8) | u16::from(index);
+
+        // Standard device-to-host request to the device
+        self.control_in(0x80, ControlRequest::GET_DESCRIPTOR, value, lang_id, 0xffff).boxed()
+    }
+}
+
+fn setup_packet(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let value = value.to_le_bytes();
+    let index = index.to_le_bytes();
+    let length = length.to_le_bytes();
+
+    [request_type, request, value[0], value[1], index[0], index[1], length[0], length[1]]
+}
+
+async fn next_response<S: AsyncRead + AsyncWrite + Unpin>(framed: &mut Framed<S, HostCodec>)
+    -> Result<Response, Error>
+{
+    match framed.next().await {
+        Some(res) => res,
+        None => Err(Error::Io(io::ErrorKind::UnexpectedEof.into())),
+    }
+}
+
+fn unexpected(res: &Response) -> Error {
+    Error::Protocol(format!("unexpected response: {:?}", res))
+}
@@ -1,9 +1,33 @@
+pub mod access;
+pub mod descriptor;
 pub mod endpoint;
+pub mod lint;
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+mod error;
+pub use error::Error;
 
 mod usbcore;
 pub use usbcore::UsbCore;
 
+mod speed;
+pub use speed::Speed;
+
 mod server;
-pub use server::Server;
+pub use server::{Server, Client, Connection, Poller, PollWaker};
+
+#[cfg(unix)]
+mod forward;
+#[cfg(unix)]
+pub use forward::forward_unix;
+
+mod runner;
+pub use runner::DeviceRunner;
+
+mod host;
+pub use host::{UsbIpHost, ImportedDevice, Completion};
 
-mod protocol;
\ No newline at end of file
+mod protocol;
+pub use protocol::{DeviceInfo, DeviceInterfaceInfo, InterfaceInfo};
@@ -0,0 +1,676 @@
+//! Conformance checks for device descriptors.
+//!
+//! Hosts tend to reject malformed descriptors without saying why (Linux may just log "config
+//! index 0 descriptor too short" or skip an interface), so checking them on the device side can
+//! save a lot of guesswork.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use usb_device::endpoint::EndpointType;
+use crate::Speed;
+use crate::descriptor::{ConfigurationDescriptor, DeviceDescriptors, EndpointDescriptor};
+
+// Device class for devices using interface association descriptors
+const CLASS_MISCELLANEOUS: u8 = 0xef;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub enum Severity {
+    /// Likely to work, but not what the specification says.
+    Warning,
+
+    /// Likely to be rejected by hosts.
+    Error,
+}
+
+/// The check that found an issue.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LintKind {
+    /// bNumInterfaces doesn't match the interfaces present, or interfaces are not numbered
+    /// consecutively.
+    NumInterfaces,
+
+    /// An interface has no alternate setting zero.
+    AlternateSetting,
+
+    /// bNumEndpoints doesn't match the endpoints present.
+    NumEndpoints,
+
+    /// An endpoint address is used more than once, or is zero.
+    EndpointAddress,
+
+    /// A max packet size is not allowed for the speed and transfer type.
+    MaxPacketSize,
+
+    /// A bInterval is out of range for the speed and transfer type.
+    Interval,
+
+    /// An interface association refers to missing or already associated interfaces.
+    InterfaceAssociation,
+
+    /// A string index refers to a string that doesn't exist.
+    StringIndex,
+}
+
+/// Where in the descriptors an issue was found.
+#[derive(Clone, Default, Eq, PartialEq, Debug)]
+pub struct Location {
+    /// bConfigurationValue of the configuration.
+    pub configuration: Option<u8>,
+
+    /// Interface number and alternate setting.
+    pub interface: Option<(u8, u8)>,
+
+    /// Endpoint address.
+    pub endpoint: Option<u8>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.configuration {
+            Some(config) => write!(f, "configuration {}", config)?,
+            None => write!(f, "device")?,
+        }
+
+        if let Some((number, alt)) = self.interface {
+            write!(f, ", interface {} alt {}", number, alt)?;
+        }
+
+        if let Some(address) = self.endpoint {
+            write!(f, ", endpoint {:#04x}", address)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    pub kind: LintKind,
+    pub location: Location,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}: {}", self.severity, self.location, self.message)
+    }
+}
+
+/// Issues found in a set of descriptors.
+#[derive(Clone, Default, Debug)]
+pub struct Report {
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    /// Returns true if there are issues with a severity of `Error`.
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|i| i.severity == Severity::Error)
+    }
+
+    fn add(&mut self, severity: Severity, kind: LintKind, location: Location, message: String) {
+        self.issues.push(Issue { severity, kind, location, message });
+    }
+}
+
+/// Checks descriptors for conformance to the USB specification for a device running at `speed`.
+pub fn lint(desc: &DeviceDescriptors, speed: Speed) -> Report {
+    let mut report = Report::default();
+
+    let dev = &desc.device;
+
+    if !speed.is_valid_max_packet_size(EndpointType::Control, u16::from(dev.max_packet_size_0)) {
+        report.add(
+            Severity::Error,
+            LintKind::MaxPacketSize,
+            Location::default(),
+            format!("bMaxPacketSize0 {} is not allowed at {:?} speed", dev.max_packet_size_0, speed));
+    }
+
+    for config in &desc.configurations {
+        lint_configuration(&mut report, speed, desc, config);
+    }
+
+    lint_strings(&mut report, desc);
+
+    report
+}
+
+fn lint_configuration(
+    report: &mut Report,
+    speed: Speed,
+    desc: &DeviceDescriptors,
+    config: &ConfigurationDescriptor)
+{
+    let config_location = Location {
+        configuration: Some(config.configuration_value),
+        ..Location::default()
+    };
+
+    if usize::from(config.num_interfaces) != config.interfaces.len() {
+        report.add(
+            Severity::Error,
+            LintKind::NumInterfaces,
+            config_location.clone(),
+            format!(
+                "bNumInterfaces is {} but {} interfaces are present",
+                config.num_interfaces,
+                config.interfaces.len()));
+    }
+
+    let numbers = config.interfaces.iter().map(|i| i.number).collect::<BTreeSet<_>>();
+
+    if numbers.iter().copied().ne(0..config.interfaces.len() as u8) {
+        report.add(
+            Severity::Warning,
+            LintKind::NumInterfaces,
+            config_location.clone(),
+            format!("interfaces are not numbered consecutively from zero: {:?}", numbers));
+    }
+
+    // Endpoint addresses and the interface they belong to
+    let mut owners = HashMap::new();
+
+    for iface in &config.interfaces {
+        if !iface.alt_settings.iter().any(|alt| alt.alternate_setting == 0) {
+            report.add(
+                Severity::Error,
+                LintKind::AlternateSetting,
+                Location {
+                    interface: Some((iface.number, 0)),
+                    ..config_location.clone()
+                },
+                "interface has no default alternate setting".into());
+        }
+
+        for alt in &iface.alt_settings {
+            let alt_location = Location {
+                interface: Some((iface.number, alt.alternate_setting)),
+                ..config_location.clone()
+            };
+
+            if usize::from(alt.num_endpoints) != alt.endpoints.len() {
+                report.add(
+                    Severity::Error,
+                    LintKind::NumEndpoints,
+                    alt_location.clone(),
+                    format!(
+                        "bNumEndpoints is {} but {} endpoints are present",
+                        alt.num_endpoints,
+                        alt.endpoints.len()));
+            }
+
+            let mut addresses = BTreeSet::new();
+
+            for ep in &alt.endpoints {
+                let location = Location {
+                    endpoint: Some(ep.address),
+                    ..alt_location.clone()
+                };
+
+                if ep.address & 0x0f == 0 {
+                    report.add(
+                        Severity::Error,
+                        LintKind::EndpointAddress,
+                        location.clone(),
+                        "endpoint zero must not have an endpoint descriptor".into());
+                }
+
+                if !addresses.insert(ep.address) {
+                    report.add(
+                        Severity::Error,
+                        LintKind::EndpointAddress,
+                        location.clone(),
+                        "endpoint address is used more than once in the same alternate setting".into());
+                }
+
+                match owners.insert(ep.address, iface.number) {
+                    Some(other) if other != iface.number => {
+                        report.add(
+                            Severity::Error,
+                            LintKind::EndpointAddress,
+                            location.clone(),
+                            format!("endpoint address is also used by interface {}", other));
+                    },
+                    _ => { },
+                }
+
+                lint_endpoint(report, speed, ep, location);
+            }
+        }
+    }
+
+    // Interfaces already claimed by an association
+    let mut associated = BTreeSet::new();
+
+    for iad in &config.associations {
+        let location = Location {
+            interface: Some((iad.first_interface, 0)),
+            ..config_location.clone()
+        };
+
+        if iad.interface_count == 0 {
+            report.add(
+                Severity::Error,
+                LintKind::InterfaceAssociation,
+                location.clone(),
+                "association has a bInterfaceCount of zero".into());
+        }
+
+        for number in iad.first_interface..iad.first_interface.saturating_add(iad.interface_count) {
+            if !numbers.contains(&number) {
+                report.add(
+                    Severity::Error,
+                    LintKind::InterfaceAssociation,
+                    location.clone(),
+                    format!("association refers to interface {}, which doesn't exist", number));
+            }
+
+            if !associated.insert(number) {
+                report.add(
+                    Severity::Error,
+                    LintKind::InterfaceAssociation,
+                    location.clone(),
+                    format!("interface {} is part of more than one association", number));
+            }
+        }
+    }
+
+    if !config.associations.is_empty() && desc.device.device_class != CLASS_MISCELLANEOUS {
+        report.add(
+            Severity::Warning,
+            LintKind::InterfaceAssociation,
+            config_location,
+            "devices with interface associations should use device class 0xef, subclass 0x02, protocol 0x01".into());
+    }
+}
+
+fn lint_endpoint(report: &mut Report, speed: Speed, ep: &EndpointDescriptor, location: Location) {
+    let ep_type = match ep.attributes & 0x03 {
+        0 => EndpointType::Control,
+        1 => EndpointType::Isochronous,
+        2 => EndpointType::Bulk,
+        _ => EndpointType::Interrupt,
+    };
+
+    if !speed.is_valid_max_packet_size(ep_type, ep.max_packet_size) {
+        report.add(
+            Severity::Error,
+            LintKind::MaxPacketSize,
+            location.clone(),
+            format!(
+                "wMaxPacketSize {:#06x} is not allowed for a {:?} endpoint at {:?} speed",
+                ep.max_packet_size,
+                ep_type,
+                speed));
+    }
+
+    let periodic = ep_type == EndpointType::Interrupt || ep_type == EndpointType::Isochronous;
+
+    if periodic && speed.interval(ep_type, ep.interval).is_none() {
+        report.add(
+            Severity::Error,
+            LintKind::Interval,
+            location,
+            format!(
+                "bInterval {} is out of range for a {:?} endpoint at {:?} speed",
+                ep.interval,
+                ep_type,
+                speed));
+    }
+}
+
+fn lint_strings(report: &mut Report, desc: &DeviceDescriptors) {
+    let dev = &desc.device;
+
+    let mut check = |index: u8, location: Location, what: &str| {
+        if index == 0 {
+            return;
+        }
+
+        if desc.lang_ids.is_empty() {
+            report.add(
+                Severity::Error,
+                LintKind::StringIndex,
+                location,
+                format!("{} refers to string {}, but the device has no strings", what, index));
+
+            return;
+        }
+
+        for lang_id in &desc.lang_ids {
+            let found = desc.strings.get(lang_id).map(|s| s.contains_key(&index)).unwrap_or(false);
+
+            if !found {
+                report.add(
+                    Severity::Error,
+                    LintKind::StringIndex,
+                    location.clone(),
+                    format!("{} refers to string {}, which doesn't exist for language {:#06x}", what, index, lang_id));
+            }
+        }
+    };
+
+    check(dev.manufacturer_index, Location::default(), "iManufacturer");
+    check(dev.product_index, Location::default(), "iProduct");
+    check(dev.serial_number_index, Location::default(), "iSerialNumber");
+
+    for config in &desc.configurations {
+        let config_location = Location {
+            configuration: Some(config.configuration_value),
+            ..Location::default()
+        };
+
+        check(config.configuration_index, config_location.clone(), "iConfiguration");
+
+        for iad in &config.associations {
+            let location = Location {
+                interface: Some((iad.first_interface, 0)),
+                ..config_location.clone()
+            };
+
+            check(iad.function_index, location, "iFunction");
+        }
+
+        for iface in &config.interfaces {
+            for alt in &iface.alt_settings {
+                let location = Location {
+                    interface: Some((iface.number, alt.alternate_setting)),
+                    ..config_location.clone()
+                };
+
+                check(alt.interface_index, location, "iInterface");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::descriptor::*;
+
+    fn ep(address: u8, attributes: u8, max_packet_size: u16, interval: u8) -> EndpointDescriptor {
+        EndpointDescriptor {
+            address,
+            attributes,
+            max_packet_size,
+            interval,
+            extra: Vec::new(),
+        }
+    }
+
+    fn interface(number: u8, endpoints: Vec<EndpointDescriptor>) -> Interface {
+        Interface {
+            number,
+            alt_settings: vec![InterfaceDescriptor {
+                interface_number: number,
+                alternate_setting: 0,
+                num_endpoints: endpoints.len() as u8,
+                interface_class: 0xff,
+                interface_subclass: 0,
+                interface_protocol: 0,
+                interface_index: 0,
+                endpoints,
+                extra: Vec::new(),
+            }],
+        }
+    }
+
+    /// A full speed device with a two interface function, like a CDC ACM serial port, that passes
+    /// every check.
+    fn clean() -> DeviceDescriptors {
+        let mut strings = BTreeMap::new();
+        strings.insert(1, "Manufacturer".to_owned());
+        strings.insert(2, "Product".to_owned());
+        strings.insert(3, "Serial port".to_owned());
+
+        DeviceDescriptors {
+            device: DeviceDescriptor {
+                usb_version: 0x0200,
+                device_class: CLASS_MISCELLANEOUS,
+                device_subclass: 0x02,
+                device_protocol: 0x01,
+                max_packet_size_0: 64,
+                vendor_id: 0x16c0,
+                product_id: 0x27dd,
+                device_version: 0x0100,
+                manufacturer_index: 1,
+                product_index: 2,
+                serial_number_index: 0,
+                num_configurations: 1,
+            },
+            configurations: vec![ConfigurationDescriptor {
+                total_length: 0,
+                num_interfaces: 2,
+                configuration_value: 1,
+                configuration_index: 0,
+                attributes: 0x80,
+                max_power: 50,
+                interfaces: vec![
+                    interface(0, vec![ep(0x81, 0x03, 8, 255)]),
+                    interface(1, vec![ep(0x02, 0x02, 64, 0), ep(0x82, 0x02, 64, 0)]),
+                ],
+                associations: vec![InterfaceAssociationDescriptor {
+                    first_interface: 0,
+                    interface_count: 2,
+                    function_class: 0x02,
+                    function_subclass: 0x02,
+                    function_protocol: 0x00,
+                    function_index: 3,
+                }],
+                extra: Vec::new(),
+            }],
+            lang_ids: vec![0x0409],
+            strings: vec![(0x0409, strings)].into_iter().collect(),
+        }
+    }
+
+    fn config(desc: &mut DeviceDescriptors) -> &mut ConfigurationDescriptor {
+        &mut desc.configurations[0]
+    }
+
+    fn alt(desc: &mut DeviceDescriptors, number: usize) -> &mut InterfaceDescriptor {
+        &mut desc.configurations[0].interfaces[number].alt_settings[0]
+    }
+
+    /// Checks that the report has issues, and that they are all of the kind `kind` and severity
+    /// `severity`.
+    fn expect_only(report: &Report, kind: LintKind, severity: Severity) {
+        assert!(!report.issues.is_empty(), "expected {:?} issues, found none", kind);
+
+        for issue in &report.issues {
+            assert_eq!((issue.kind, issue.severity), (kind, severity), "unexpected issue: {}", issue);
+        }
+    }
+
+    #[test]
+    fn clean_descriptors() {
+        let report = lint(&clean(), Speed::Full);
+
+        assert!(report.issues.is_empty(), "unexpected issues: {:?}", report.issues);
+        assert!(!report.has_errors());
+    }
+
+    #[test]
+    fn num_interfaces() {
+        let mut desc = clean();
+        config(&mut desc).num_interfaces = 3;
+
+        let report = lint(&desc, Speed::Full);
+
+        expect_only(&report, LintKind::NumInterfaces, Severity::Error);
+        assert_eq!(report.issues[0].location.configuration, Some(1));
+    }
+
+    #[test]
+    fn interfaces_not_consecutive() {
+        let mut desc = clean();
+        config(&mut desc).associations.clear();
+        config(&mut desc).interfaces[1].number = 2;
+        alt(&mut desc, 1).interface_number = 2;
+
+        expect_only(&lint(&desc, Speed::Full), LintKind::NumInterfaces, Severity::Warning);
+    }
+
+    #[test]
+    fn alternate_setting() {
+        let mut desc = clean();
+        alt(&mut desc, 1).alternate_setting = 1;
+
+        let report = lint(&desc, Speed::Full);
+
+        expect_only(&report, LintKind::AlternateSetting, Severity::Error);
+        assert_eq!(report.issues[0].location.interface, Some((1, 0)));
+    }
+
+    #[test]
+    fn num_endpoints() {
+        let mut desc = clean();
+        alt(&mut desc, 1).num_endpoints = 3;
+
+        expect_only(&lint(&desc, Speed::Full), LintKind::NumEndpoints, Severity::Error);
+    }
+
+    #[test]
+    fn endpoint_zero() {
+        let mut desc = clean();
+        alt(&mut desc, 1).endpoints[0].address = 0x00;
+
+        let report = lint(&desc, Speed::Full);
+
+        expect_only(&report, LintKind::EndpointAddress, Severity::Error);
+        assert_eq!(report.issues[0].location.endpoint, Some(0x00));
+    }
+
+    #[test]
+    fn duplicate_endpoint_address() {
+        let mut desc = clean();
+
+        let data = alt(&mut desc, 1);
+        data.endpoints.push(ep(0x02, 0x02, 64, 0));
+        data.num_endpoints += 1;
+
+        expect_only(&lint(&desc, Speed::Full), LintKind::EndpointAddress, Severity::Error);
+    }
+
+    #[test]
+    fn endpoint_address_shared_between_interfaces() {
+        let mut desc = clean();
+
+        let data = alt(&mut desc, 1);
+        data.endpoints.push(ep(0x81, 0x02, 64, 0));
+        data.num_endpoints += 1;
+
+        let report = lint(&desc, Speed::Full);
+
+        expect_only(&report, LintKind::EndpointAddress, Severity::Error);
+        assert!(report.issues[0].message.contains("interface 0"), "{}", report.issues[0]);
+    }
+
+    #[test]
+    fn max_packet_size() {
+        let mut desc = clean();
+        alt(&mut desc, 1).endpoints[0].max_packet_size = 63;
+
+        expect_only(&lint(&desc, Speed::Full), LintKind::MaxPacketSize, Severity::Error);
+
+        // Full speed bulk endpoints are too small for high speed
+        let mut desc = clean();
+        alt(&mut desc, 0).endpoints[0].interval = 4;
+
+        let report = lint(&desc, Speed::High);
+
+        expect_only(&report, LintKind::MaxPacketSize, Severity::Error);
+        assert_eq!(report.issues.len(), 2);
+    }
+
+    #[test]
+    fn max_packet_size_0() {
+        let mut desc = clean();
+        desc.device.max_packet_size_0 = 8;
+
+        assert!(lint(&desc, Speed::Full).issues.is_empty());
+
+        desc.device.max_packet_size_0 = 12;
+
+        let report = lint(&desc, Speed::Full);
+
+        expect_only(&report, LintKind::MaxPacketSize, Severity::Error);
+        assert_eq!(report.issues[0].location, Location::default());
+    }
+
+    #[test]
+    fn interval() {
+        let mut desc = clean();
+        alt(&mut desc, 0).endpoints[0].interval = 0;
+
+        expect_only(&lint(&desc, Speed::Full), LintKind::Interval, Severity::Error);
+    }
+
+    #[test]
+    fn association_without_interfaces() {
+        let mut desc = clean();
+        config(&mut desc).associations[0].interface_count = 0;
+
+        expect_only(&lint(&desc, Speed::Full), LintKind::InterfaceAssociation, Severity::Error);
+    }
+
+    #[test]
+    fn association_with_missing_interface() {
+        let mut desc = clean();
+        config(&mut desc).associations[0].interface_count = 3;
+
+        let report = lint(&desc, Speed::Full);
+
+        expect_only(&report, LintKind::InterfaceAssociation, Severity::Error);
+        assert!(report.issues[0].message.contains("interface 2"), "{}", report.issues[0]);
+    }
+
+    #[test]
+    fn overlapping_associations() {
+        let mut desc = clean();
+
+        let mut second = config(&mut desc).associations[0].clone();
+        second.first_interface = 1;
+        second.interface_count = 1;
+        config(&mut desc).associations.push(second);
+
+        expect_only(&lint(&desc, Speed::Full), LintKind::InterfaceAssociation, Severity::Error);
+    }
+
+    #[test]
+    fn association_device_class() {
+        let mut desc = clean();
+        desc.device.device_class = 0x00;
+
+        expect_only(&lint(&desc, Speed::Full), LintKind::InterfaceAssociation, Severity::Warning);
+    }
+
+    #[test]
+    fn missing_string() {
+        let mut desc = clean();
+        alt(&mut desc, 1).interface_index = 4;
+
+        let report = lint(&desc, Speed::Full);
+
+        expect_only(&report, LintKind::StringIndex, Severity::Error);
+        assert_eq!(report.issues[0].location.interface, Some((1, 0)));
+    }
+
+    #[test]
+    fn strings_without_languages() {
+        let mut desc = clean();
+        desc.lang_ids.clear();
+        desc.strings.clear();
+
+        let report = lint(&desc, Speed::Full);
+
+        expect_only(&report, LintKind::StringIndex, Severity::Error);
+
+        // iManufacturer, iProduct and iFunction
+        assert_eq!(report.issues.len(), 3);
+    }
+}
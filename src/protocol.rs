@@ -1,4 +1,5 @@
-use std::io::{self, Cursor};
+use std::collections::HashMap;
+use std::fmt;
 use std::sync::Arc;
 use bytes::*;
 use tokio_util::codec::{Encoder, Decoder};
@@ -6,6 +7,8 @@ use tokio_util::codec::{Encoder, Decoder};
 use usb_device::UsbDirection;
 use usb_device::endpoint::EndpointAddress;
 
+use crate::Error;
+
 const VERSION: u32 = 0x01110000;
 const OP_REQ_DEVLIST: u32 = VERSION | 0x8005;
 const OP_REP_DEVLIST: u32 = VERSION | 0x0005;
@@ -16,6 +19,11 @@ const OP_RET_SUBMIT: u32 = 0x00000003;
 const OP_CMD_UNLINK: u32 = 0x00000002;
 const OP_RET_UNLINK: u32 = 0x00000004;
 
+// URB transfer_flags, as defined by Linux
+pub const URB_SHORT_NOT_OK: u32 = 0x0001;
+pub const URB_ZERO_PACKET: u32 = 0x0040;
+pub const URB_DIR_IN: u32 = 0x0200;
+
 #[derive(Debug)]
 pub enum Request {
     DevList,
@@ -65,7 +73,7 @@ pub struct InterfaceInfo {
 
 #[derive(Debug)]
 pub struct ImportResponse {
-    pub status: u32,
+    pub status: OpStatus,
     pub device: Option<Arc<DeviceInfo>>,
 }
 
@@ -81,6 +89,7 @@ pub struct SubmitRequest {
     pub interval: u32,
     pub setup: Option<[u8; 8]>,
     pub data: BytesMut,
+    pub iso_packets: Vec<IsoPacketDescriptor>,
 }
 
 #[derive(Debug)]
@@ -88,13 +97,23 @@ pub struct SubmitResponse {
     pub seqnum: u32,
     pub devid: u32,
     pub ep: EndpointAddress,
-    pub status: u32,
-    pub actual_length: u32, // TODO: does this need to be filled in for OUT transactions?
+    pub status: i32,
+    pub actual_length: u32,
     pub actual_start_frame: u32,
     pub number_of_packets: u32,
     pub error_count: u32,
     pub setup: Option<[u8; 8]>,
-    pub data: BytesMut,
+    pub data: BytesMut, // for isochronous IN transfers, the packets are packed back to back
+    pub iso_packets: Vec<IsoPacketDescriptor>,
+}
+
+/// Describes one packet (frame) of an isochronous transfer.
+#[derive(Clone, Debug)]
+pub struct IsoPacketDescriptor {
+    pub offset: u32,
+    pub length: u32,
+    pub actual_length: u32,
+    pub status: i32,
 }
 
 #[derive(Debug)]
@@ -110,46 +129,205 @@ pub struct UnlinkResponse {
     pub seqnum: u32,
     pub devid: u32,
     pub ep: EndpointAddress,
-    pub status: u32,
+    pub status: i32,
     pub unlink_seqnum: u32,
 }
 
+/// Status of an OP_REP_* reply, as used by the Linux usbipd.
+#[repr(u32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OpStatus {
+    Ok = 0,
+    NotAvailable = 1, // ST_NA
+    DeviceBusy = 2, // ST_DEV_BUSY
+    DeviceError = 3, // ST_DEV_ERR
+    NoDevice = 4, // ST_NODEV
+    Error = 5, // ST_ERROR
+}
+
 #[repr(u32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum ResponseStatus {
     Ok = 0,
+    NoDevice = 19, // ENODEV
     EndpointStalled = 32, // EPIPE
+    ProtocolError = 71, // EPROTO
+    Overflow = 75, // EOVERFLOW
     Unlinked = 104, // ECONNRESET
     ShortTransfer = 121, // EREMOTEIO
 }
 
-fn invalid_data() -> io::Error {
-    io::Error::from(io::ErrorKind::InvalidData)
+impl OpStatus {
+    fn from_u32(status: u32) -> Option<OpStatus> {
+        Some(match status {
+            0 => OpStatus::Ok,
+            1 => OpStatus::NotAvailable,
+            2 => OpStatus::DeviceBusy,
+            3 => OpStatus::DeviceError,
+            4 => OpStatus::NoDevice,
+            5 => OpStatus::Error,
+            _ => return None,
+        })
+    }
+}
+
+impl ResponseStatus {
+    /// Returns the status as sent on the wire, which is a negated Linux errno value.
+    pub fn code(self) -> i32 {
+        -(self as i32)
+    }
+}
+
+/// Formats a SETUP packet for diagnostics.
+pub struct DisplaySetup<'a>(pub &'a [u8; 8]);
+
+impl fmt::Display for DisplaySetup<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = self.0;
+
+        write!(f,
+            "bmRequestType={:#04x} bRequest={:#04x} wValue={:#06x} wIndex={:#06x} wLength={}",
+            s[0],
+            s[1],
+            u16::from_le_bytes([s[2], s[3]]),
+            u16::from_le_bytes([s[4], s[5]]),
+            u16::from_le_bytes([s[6], s[7]]))
+    }
+}
+
+fn invalid_data(op: u32, offset: u64, msg: impl fmt::Display) -> Error {
+    Error::Protocol(format!("{} (opcode {:#010x} at offset {})", msg, op, offset))
+}
+
+fn peek_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+/// Splits the next complete PDU off `src`, or returns `None` if it hasn't been fully received yet.
+/// `pdu_len` returns the length of a PDU with the given opcode, or `None` if not enough of it has
+/// been received yet to tell. `offset` is the position of `src` in the stream, for error messages.
+fn split_pdu(
+    src: &mut BytesMut,
+    offset: u64,
+    pdu_len: impl FnOnce(u32, &[u8]) -> Result<Option<usize>, Error>)
+    -> Result<Option<(u32, BytesMut)>, Error>
+{
+    if src.len() < 4 {
+        return Ok(None);
+    }
+
+    let op = peek_u32(src, 0);
+
+    // OP_REQ_* and OP_REP_* codes carry the protocol version in the high 16 bits
+    if op & 0xffff_0000 != 0 && op & 0xffff_0000 != VERSION {
+        return Err(invalid_data(op, offset, format!(
+            "unsupported protocol version {:#06x}",
+            op >> 16)));
+    }
+
+    let len = match pdu_len(op, src)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+
+    if src.len() < len {
+        // Leave the partial PDU in the buffer until the rest of it has been received
+        src.reserve(len - src.len());
+        return Ok(None);
+    }
+
+    Ok(Some((op, src.split_to(len))))
 }
 
-pub struct UsbIpCodec;
+/// Default limit for the transfer buffer length of a single URB.
+pub const DEFAULT_MAX_TRANSFER_SIZE: usize = 16 * 1024 * 1024;
+
+// Maximum number of packets in an isochronous URB, same as in the Linux driver
+const MAX_ISO_PACKETS: u32 = 1024;
+
+pub struct UsbIpCodec {
+    max_transfer_size: usize,
+    // Number of bytes decoded so far, for error messages
+    offset: u64,
+}
 
 impl UsbIpCodec {
     const DEVICE_INFO_SIZE: usize = 256 + 32 + (3 * 4) + (3 * 2) + 6;
     const INTERFACE_INFO_SIZE: usize = 4;
     const URB_HEADER_SIZE: usize = 4 * 4;
     const PDU_LENGTH: usize = 48;
+    const ISO_PACKET_DESCRIPTOR_SIZE: usize = 4 * 4;
 
-    pub fn new() -> Self {
-        UsbIpCodec
+    /// Creates a codec that rejects URBs with a transfer buffer longer than `max_transfer_size`.
+    pub fn with_max_transfer_size(max_transfer_size: usize) -> Self {
+        UsbIpCodec {
+            max_transfer_size,
+            offset: 0,
+        }
+    }
+
+    /// Returns the length of the request at the start of `src`, or `None` if not enough of it has
+    /// been received yet to tell.
+    fn request_len(&self, op: u32, src: &[u8]) -> Result<Option<usize>, Error> {
+        let len = match op {
+            OP_REQ_DEVLIST => 4 + 4,
+            OP_REQ_IMPORT => 4 + 4 + 32,
+            OP_CMD_UNLINK => Self::PDU_LENGTH,
+            OP_CMD_SUBMIT => {
+                if src.len() < Self::PDU_LENGTH {
+                    return Ok(None);
+                }
+
+                let direction = peek_u32(src, 12);
+                let transfer_buffer_length = peek_u32(src, 24);
+                let number_of_packets = peek_u32(src, 32);
+
+                if transfer_buffer_length as usize > self.max_transfer_size {
+                    return Err(invalid_data(op, self.offset + 24, format!(
+                        "transfer buffer length {} exceeds maximum of {}",
+                        transfer_buffer_length,
+                        self.max_transfer_size)));
+                }
+
+                if number_of_packets != 0xffff_ffff && number_of_packets > MAX_ISO_PACKETS {
+                    return Err(invalid_data(op, self.offset + 32, format!(
+                        "too many isochronous packets ({})",
+                        number_of_packets)));
+                }
+
+                // OUT data is followed by the isochronous packet descriptors, if any
+
+                let data_len = if direction == 0 { transfer_buffer_length as usize } else { 0 };
+
+                let iso_len = match number_of_packets {
+                    0xffff_ffff => 0, // non-isochronous
+                    n => n as usize * Self::ISO_PACKET_DESCRIPTOR_SIZE,
+                };
+
+                Self::PDU_LENGTH + data_len + iso_len
+            },
+            _ => {
+                return Err(invalid_data(op, self.offset, "unknown opcode"));
+            },
+        };
+
+        Ok(Some(len))
     }
 
-    fn decode_urb_header(c: &mut Cursor<BytesMut>) -> io::Result<(u32, u32, EndpointAddress)> {
-        let seqnum = c.get_u32();
-        let devid = c.get_u32();
+    fn decode_urb_header(op: u32, offset: u64, buf: &mut BytesMut)
+        -> Result<(u32, u32, EndpointAddress), Error>
+    {
+        let seqnum = buf.get_u32();
+        let devid = buf.get_u32();
 
-        let direction = c.get_u32();
+        let direction = buf.get_u32();
         if !(direction <= 1) {
-            return Err(invalid_data());
+            return Err(invalid_data(op, offset + 12, format!("invalid direction {}", direction)));
         }
 
-        let ep = c.get_u32();
+        let ep = buf.get_u32();
         if !(ep <= 15) {
-            return Err(invalid_data());
+            return Err(invalid_data(op, offset + 16, format!("invalid endpoint number {}", ep)));
         }
 
         let ep = EndpointAddress::from_parts(
@@ -159,6 +337,22 @@ impl UsbIpCodec {
         return Ok((seqnum, devid, ep));
     }
 
+    fn decode_iso_packet(buf: &mut BytesMut) -> IsoPacketDescriptor {
+        IsoPacketDescriptor {
+            offset: buf.get_u32(),
+            length: buf.get_u32(),
+            actual_length: buf.get_u32(),
+            status: buf.get_i32(),
+        }
+    }
+
+    fn encode_iso_packet(packet: &IsoPacketDescriptor, buf: &mut BytesMut) {
+        buf.put_u32(packet.offset);
+        buf.put_u32(packet.length);
+        buf.put_u32(packet.actual_length);
+        buf.put_i32(packet.status);
+    }
+
     fn encode_device_info(dev: &DeviceInfo, buf: &mut BytesMut) {
         let mut path = [0u8; 256];
         path[..dev.path.len()].copy_from_slice(dev.path.as_bytes());
@@ -201,61 +395,48 @@ impl UsbIpCodec {
 
 impl Decoder for UsbIpCodec {
     type Item = Request;
-    type Error = io::Error;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let mut c = Cursor::new(src.clone());
+        let (op, mut frame) = match split_pdu(src, self.offset, |op, src| self.request_len(op, src))? {
+            Some(pdu) => pdu,
+            None => return Ok(None),
+        };
 
-        if c.remaining() < 4 {
-            return Ok(None);
-        }
+        let len = frame.len();
 
-        //println!("decoding {:02x?}", &src[..]);
+        let offset = self.offset;
+        self.offset += len as u64;
 
-        let op = c.get_u32();
+        frame.advance(4); // op
 
         let item = match op {
             OP_REQ_DEVLIST => {
-                if c.remaining() < 4 {
-                    return Ok(None);
-                }
-
-                c.get_u32(); // status (unused)
-
-                src.advance(c.position() as usize);
+                frame.get_u32(); // status (unused)
 
                 Request::DevList
             },
             OP_REQ_IMPORT => {
-                if c.remaining() < 4 + 32 {
-                    return Ok(None);
-                }
-
-                c.get_u32(); // status (unused)
-
-                let mut busname = [0u8; 32];
-                c.copy_to_slice(&mut busname);
-                let busname = String::from_utf8_lossy(&busname).trim_end_matches('\0').to_string();
+                frame.get_u32(); // status (unused)
 
-                src.advance(c.position() as usize);
+                let busname = String::from_utf8_lossy(&frame[..32]).trim_end_matches('\0').to_string();
 
                 Request::Import(busname)
             },
             OP_CMD_SUBMIT => {
-                if c.remaining() < Self::PDU_LENGTH - 4 {
-                    return Ok(None);
-                }
-
-                let (seqnum, devid, ep) = Self::decode_urb_header(&mut c)?;
-
-                let transfer_flags = c.get_u32();
-                let transfer_buffer_length = c.get_u32();
-                let start_frame = c.get_u32();
-                let number_of_packets = c.get_u32();
-                let interval = c.get_u32();
+                let (seqnum, devid, ep) = Self::decode_urb_header(op, offset, &mut frame)?;
+
+                let transfer_flags = frame.get_u32();
+                let transfer_buffer_length = frame.get_u32();
+                let start_frame = frame.get_u32();
+                let number_of_packets = match frame.get_u32() {
+                    0xffff_ffff => 0, // non-isochronous
+                    n => n,
+                };
+                let interval = frame.get_u32();
 
                 let mut setup = [0u8; 8];
-                c.copy_to_slice(&mut setup);
+                frame.copy_to_slice(&mut setup);
 
                 let setup = if setup.iter().any(|&b| b != 0x00) {
                     Some(setup)
@@ -263,19 +444,27 @@ impl Decoder for UsbIpCodec {
                     None
                 };
 
-                assert!(c.position() == Self::PDU_LENGTH as u64);
+                let data_len = len - Self::PDU_LENGTH
+                    - number_of_packets as usize * Self::ISO_PACKET_DESCRIPTOR_SIZE;
 
-                src.advance(Self::PDU_LENGTH);
+                let data = frame.split_to(data_len);
 
-                let data = if ep.direction() == UsbDirection::Out {
-                    if c.remaining() < transfer_buffer_length as usize {
-                        return Ok(None);
+                let iso_offset = offset + (Self::PDU_LENGTH + data_len) as u64;
+
+                let mut iso_packets = Vec::with_capacity(number_of_packets as usize);
+
+                for i in 0..number_of_packets {
+                    let packet = Self::decode_iso_packet(&mut frame);
+
+                    if packet.offset as u64 + packet.length as u64 > transfer_buffer_length as u64 {
+                        return Err(invalid_data(
+                            op,
+                            iso_offset + (i as usize * Self::ISO_PACKET_DESCRIPTOR_SIZE) as u64,
+                            format!("isochronous packet {} outside of transfer buffer", i)));
                     }
 
-                    src.split_to(transfer_buffer_length as usize).into()
-                } else {
-                    BytesMut::new()
-                };
+                    iso_packets.push(packet);
+                }
 
                 Request::Submit(SubmitRequest {
                     seqnum,
@@ -288,18 +477,15 @@ impl Decoder for UsbIpCodec {
                     interval,
                     setup,
                     data,
+                    iso_packets,
                 })
             },
             OP_CMD_UNLINK => {
-                if c.remaining() < Self::PDU_LENGTH - 4 {
-                    return Ok(None);
-                }
+                let (seqnum, devid, ep) = Self::decode_urb_header(op, offset, &mut frame)?;
 
-                let (seqnum, devid, ep) = Self::decode_urb_header(&mut c)?;
+                let unlink_seqnum = frame.get_u32();
 
-                let unlink_seqnum = c.get_u32();
-
-                src.advance(Self::PDU_LENGTH as usize);
+                // The rest of the PDU is padding
 
                 Request::Unlink(UnlinkRequest {
                     seqnum,
@@ -308,22 +494,20 @@ impl Decoder for UsbIpCodec {
                     unlink_seqnum,
                 })
             },
-            _ => {
-                return Err(invalid_data());
-            }
+            _ => unreachable!(),
         };
 
-        //println!("Recv: {:?}", &item);
+        tracing::trace!(request = ?item, "recv");
 
-        return Ok(Some(item));
+        Ok(Some(item))
     }
 }
 
 impl Encoder<Response> for UsbIpCodec {
-    type Error = io::Error;
+    type Error = Error;
 
     fn encode(&mut self, msg: Response, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        //println!("Send: {:?}", &msg);
+        tracing::trace!(response = ?msg, "send");
 
         match msg {
             Response::DevList(devices) => {
@@ -354,7 +538,7 @@ impl Encoder<Response> for UsbIpCodec {
                 );
 
                 buf.put_u32(OP_REP_IMPORT); // version, reply code
-                buf.put_u32(res.status);
+                buf.put_u32(res.status as u32);
 
                 if let Some(dev) = res.device {
                     Self::encode_device_info(&dev, buf);
@@ -363,14 +547,16 @@ impl Encoder<Response> for UsbIpCodec {
             Response::Submit(res) => {
                 let data_len = res.data.len();
 
-                buf.reserve(4 + Self::URB_HEADER_SIZE + (5 * 4) + 8 + data_len);
+                buf.reserve(
+                    4 + Self::URB_HEADER_SIZE + (5 * 4) + 8 + data_len
+                    + res.iso_packets.len() * Self::ISO_PACKET_DESCRIPTOR_SIZE);
 
                 buf.put_u32(OP_RET_SUBMIT);
 
                 Self::encode_urb_header(res.seqnum, res.devid, res.ep, buf);
 
-                buf.put_u32(res.status);
-                buf.put_u32(data_len as u32); // actual_length
+                buf.put_i32(res.status);
+                buf.put_u32(res.actual_length);
                 buf.put_u32(res.actual_start_frame);
                 buf.put_u32(res.number_of_packets);
                 buf.put_u32(res.error_count);
@@ -378,24 +564,547 @@ impl Encoder<Response> for UsbIpCodec {
                 //buf.put_slice(&res.setup.unwrap_or([0u8; 8]));
                 buf.put_u64(0); // SETUP
 
-                assert!(buf.len() == Self::PDU_LENGTH);
-
                 buf.put_slice(&res.data);
+
+                for packet in &res.iso_packets {
+                    Self::encode_iso_packet(packet, buf);
+                }
             },
             Response::Unlink(res) => {
-                buf.reserve(4 + Self::URB_HEADER_SIZE + 4);
+                buf.reserve(Self::PDU_LENGTH);
 
                 buf.put_u32(OP_RET_UNLINK);
 
                 Self::encode_urb_header(res.seqnum, res.devid, res.ep, buf);
-                buf.put_u32(res.status);
+                buf.put_i32(res.status);
+
+                // Pad to the full PDU length
+                buf.put_slice(&[0u8; Self::PDU_LENGTH - (4 + Self::URB_HEADER_SIZE + 4)]);
+            },
+        }
+
+        Ok(())
+    }
+}
+
+enum PendingRequest {
+    Submit(u32, EndpointAddress),
+    Unlink(u32, EndpointAddress, u32),
+}
+
+/// The host side counterpart of `UsbIpCodec`. Encodes requests and decodes responses.
+pub struct HostCodec {
+    max_transfer_size: usize,
+    // RET_* PDUs sent by Linux don't identify the device or endpoint, so they are remembered here
+    // until the response arrives
+    pending: HashMap<u32, PendingRequest>,
+    // Number of bytes decoded so far, for error messages
+    offset: u64,
+}
 
-                while buf.len() < Self::PDU_LENGTH {
-                    buf.put_u8(0);
+impl HostCodec {
+    pub fn new() -> Self {
+        Self::with_max_transfer_size(DEFAULT_MAX_TRANSFER_SIZE)
+    }
+
+    /// Creates a codec that rejects responses with more than `max_transfer_size` bytes of data,
+    /// and device lists longer than that.
+    pub fn with_max_transfer_size(max_transfer_size: usize) -> Self {
+        HostCodec {
+            max_transfer_size,
+            pending: HashMap::new(),
+            offset: 0,
+        }
+    }
+
+    /// Returns the length of the response at the start of `src`, or `None` if not enough of it
+    /// has been received yet to tell.
+    fn response_len(&self, op: u32, src: &[u8]) -> Result<Option<usize>, Error> {
+        let len = match op {
+            OP_REP_DEVLIST => {
+                if src.len() < 12 {
+                    return Ok(None);
+                }
+
+                let num_devices = peek_u32(src, 8);
+
+                // Every device takes up at least this much, so an absurd count can be rejected
+                // before waiting for any of them
+                let min_len = 12 + u64::from(num_devices) * UsbIpCodec::DEVICE_INFO_SIZE as u64;
+
+                if min_len > self.max_transfer_size as u64 {
+                    return Err(invalid_data(op, self.offset + 8, format!(
+                        "device list of {} devices exceeds maximum of {} bytes",
+                        num_devices,
+                        self.max_transfer_size)));
+                }
+
+                // The number of interfaces is the last byte of each device
+                let mut pos = 12;
+                for _ in 0..num_devices {
+                    if src.len() < pos + UsbIpCodec::DEVICE_INFO_SIZE {
+                        return Ok(None);
+                    }
+
+                    let num_interfaces = src[pos + UsbIpCodec::DEVICE_INFO_SIZE - 1] as usize;
+
+                    pos += UsbIpCodec::DEVICE_INFO_SIZE
+                        + num_interfaces * UsbIpCodec::INTERFACE_INFO_SIZE;
                 }
+
+                if pos > self.max_transfer_size {
+                    return Err(invalid_data(op, self.offset, format!(
+                        "device list length {} exceeds maximum of {}",
+                        pos,
+                        self.max_transfer_size)));
+                }
+
+                pos
+            },
+            OP_REP_IMPORT => {
+                if src.len() < 8 {
+                    return Ok(None);
+                }
+
+                // The device is only sent if the import succeeded
+                match peek_u32(src, 4) {
+                    0 => 8 + UsbIpCodec::DEVICE_INFO_SIZE,
+                    _ => 8,
+                }
+            },
+            OP_RET_UNLINK => UsbIpCodec::PDU_LENGTH,
+            OP_RET_SUBMIT => {
+                if src.len() < UsbIpCodec::PDU_LENGTH {
+                    return Ok(None);
+                }
+
+                let seqnum = peek_u32(src, 4);
+                let actual_length = peek_u32(src, 24);
+                let number_of_packets = peek_u32(src, 32);
+
+                let ep = match self.pending.get(&seqnum) {
+                    Some(PendingRequest::Submit(_, ep)) => *ep,
+                    _ => {
+                        return Err(invalid_data(op, self.offset + 4, format!(
+                            "response to unknown request {}",
+                            seqnum)));
+                    },
+                };
+
+                if actual_length as usize > self.max_transfer_size {
+                    return Err(invalid_data(op, self.offset + 24, format!(
+                        "actual length {} exceeds maximum of {}",
+                        actual_length,
+                        self.max_transfer_size)));
+                }
+
+                if number_of_packets != 0xffff_ffff && number_of_packets > MAX_ISO_PACKETS {
+                    return Err(invalid_data(op, self.offset + 32, format!(
+                        "too many isochronous packets ({})",
+                        number_of_packets)));
+                }
+
+                // IN data is followed by the isochronous packet descriptors, if any
+
+                let data_len = match ep.direction() {
+                    UsbDirection::In => actual_length as usize,
+                    UsbDirection::Out => 0,
+                };
+
+                let iso_len = match number_of_packets {
+                    0xffff_ffff => 0, // non-isochronous
+                    n => n as usize * UsbIpCodec::ISO_PACKET_DESCRIPTOR_SIZE,
+                };
+
+                UsbIpCodec::PDU_LENGTH + data_len + iso_len
+            },
+            _ => {
+                return Err(invalid_data(op, self.offset, "unknown opcode"));
+            },
+        };
+
+        Ok(Some(len))
+    }
+
+    fn decode_string(buf: &mut BytesMut, len: usize) -> String {
+        let s = buf.split_to(len);
+
+        String::from_utf8_lossy(&s).trim_end_matches('\0').to_string()
+    }
+
+    fn decode_device_info(buf: &mut BytesMut) -> DeviceInfo {
+        DeviceInfo {
+            path: Self::decode_string(buf, 256),
+            busid: Self::decode_string(buf, 32),
+            busnum: buf.get_u32(),
+            devnum: buf.get_u32(),
+            speed: buf.get_u32(),
+            id_vendor: buf.get_u16(),
+            id_product: buf.get_u16(),
+            bcd_device: buf.get_u16(),
+            device_class: buf.get_u8(),
+            device_subclass: buf.get_u8(),
+            device_protocol: buf.get_u8(),
+            configuration_value: buf.get_u8(),
+            num_configuration: buf.get_u8(),
+            num_interfaces: buf.get_u8(),
+        }
+    }
+
+    fn decode_interface_info(buf: &mut BytesMut) -> InterfaceInfo {
+        let iface = InterfaceInfo {
+            interface_class: buf.get_u8(),
+            interface_subclass: buf.get_u8(),
+            interface_protocol: buf.get_u8(),
+        };
+
+        buf.get_u8(); // padding
+
+        iface
+    }
+}
+
+impl Decoder for HostCodec {
+    type Item = Response;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let (op, mut frame) = match split_pdu(src, self.offset, |op, src| self.response_len(op, src))? {
+            Some(pdu) => pdu,
+            None => return Ok(None),
+        };
+
+        let len = frame.len();
+
+        let offset = self.offset;
+        self.offset += len as u64;
+
+        frame.advance(4); // op
+
+        let item = match op {
+            OP_REP_DEVLIST => {
+                frame.get_u32(); // status (always 0)
+
+                let num_devices = frame.get_u32();
+
+                let devices = (0..num_devices)
+                    .map(|_| {
+                        let device = Self::decode_device_info(&mut frame);
+
+                        let interfaces = (0..device.num_interfaces)
+                            .map(|_| Self::decode_interface_info(&mut frame))
+                            .collect();
+
+                        Arc::new(DeviceInterfaceInfo {
+                            device: Arc::new(device),
+                            interfaces,
+                        })
+                    })
+                    .collect();
+
+                Response::DevList(devices)
+            },
+            OP_REP_IMPORT => {
+                let status = frame.get_u32();
+                let status = OpStatus::from_u32(status)
+                    .ok_or_else(|| invalid_data(op, offset + 4, format!("invalid status {}", status)))?;
+
+                let device = if frame.has_remaining() {
+                    Some(Arc::new(Self::decode_device_info(&mut frame)))
+                } else {
+                    None
+                };
+
+                Response::Import(ImportResponse {
+                    status,
+                    device,
+                })
+            },
+            OP_RET_SUBMIT => {
+                let seqnum = frame.get_u32();
+
+                let (devid, ep) = match self.pending.remove(&seqnum) {
+                    Some(PendingRequest::Submit(devid, ep)) => (devid, ep),
+                    _ => unreachable!(),
+                };
+
+                frame.advance(3 * 4); // devid, direction, ep
+
+                let status = frame.get_i32();
+                let actual_length = frame.get_u32();
+                let actual_start_frame = frame.get_u32();
+                let number_of_packets = match frame.get_u32() {
+                    0xffff_ffff => 0, // non-isochronous
+                    n => n,
+                };
+                let error_count = frame.get_u32();
+
+                frame.advance(8); // SETUP (unused)
+
+                let data_len = len - UsbIpCodec::PDU_LENGTH
+                    - number_of_packets as usize * UsbIpCodec::ISO_PACKET_DESCRIPTOR_SIZE;
+
+                let data = frame.split_to(data_len);
+
+                let iso_packets = (0..number_of_packets)
+                    .map(|_| UsbIpCodec::decode_iso_packet(&mut frame))
+                    .collect();
+
+                Response::Submit(SubmitResponse {
+                    seqnum,
+                    devid,
+                    ep,
+                    status,
+                    actual_length,
+                    actual_start_frame,
+                    number_of_packets,
+                    error_count,
+                    setup: None,
+                    data,
+                    iso_packets,
+                })
+            },
+            OP_RET_UNLINK => {
+                let seqnum = frame.get_u32();
+
+                frame.advance(3 * 4); // devid, direction, ep
+
+                let status = frame.get_i32();
+
+                // The rest of the PDU is padding
+
+                let (devid, ep, unlink_seqnum) = match self.pending.remove(&seqnum) {
+                    Some(PendingRequest::Unlink(devid, ep, unlink_seqnum)) => (devid, ep, unlink_seqnum),
+                    _ => {
+                        return Err(invalid_data(op, offset + 4, format!(
+                            "response to unknown request {}",
+                            seqnum)));
+                    },
+                };
+
+                // If the URB was unlinked, there will be no RET_SUBMIT for it
+                if status == ResponseStatus::Unlinked.code() {
+                    self.pending.remove(&unlink_seqnum);
+                }
+
+                Response::Unlink(UnlinkResponse {
+                    seqnum,
+                    devid,
+                    ep,
+                    status,
+                    unlink_seqnum,
+                })
+            },
+            _ => unreachable!(),
+        };
+
+        tracing::trace!(response = ?item, "recv");
+
+        Ok(Some(item))
+    }
+}
+
+impl Encoder<Request> for HostCodec {
+    type Error = Error;
+
+    fn encode(&mut self, msg: Request, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        tracing::trace!(request = ?msg, "send");
+
+        match msg {
+            Request::DevList => {
+                buf.reserve(2 * 4);
+
+                buf.put_u32(OP_REQ_DEVLIST); // version, request code
+                buf.put_u32(0); // status (unused)
+            },
+            Request::Import(bus_id) => {
+                if bus_id.len() > 31 {
+                    return Err(Error::Device(format!("invalid bus ID: {:?}", bus_id)));
+                }
+
+                buf.reserve(2 * 4 + 32);
+
+                buf.put_u32(OP_REQ_IMPORT); // version, request code
+                buf.put_u32(0); // status (unused)
+
+                let mut busid = [0u8; 32];
+                busid[..bus_id.len()].copy_from_slice(bus_id.as_bytes());
+                buf.extend_from_slice(&busid[..]);
+            },
+            Request::Submit(req) => {
+                let data_len = match req.ep.direction() {
+                    UsbDirection::Out => req.data.len(),
+                    UsbDirection::In => 0,
+                };
+
+                buf.reserve(
+                    UsbIpCodec::PDU_LENGTH + data_len
+                    + req.iso_packets.len() * UsbIpCodec::ISO_PACKET_DESCRIPTOR_SIZE);
+
+                buf.put_u32(OP_CMD_SUBMIT);
+
+                UsbIpCodec::encode_urb_header(req.seqnum, req.devid, req.ep, buf);
+
+                buf.put_u32(req.transfer_flags);
+                buf.put_u32(req.transfer_buffer_length);
+                buf.put_u32(req.start_frame);
+                buf.put_u32(req.iso_packets.len() as u32);
+                buf.put_u32(req.interval);
+                buf.put_slice(&req.setup.unwrap_or([0u8; 8]));
+
+                buf.put_slice(&req.data[..data_len]);
+
+                for packet in &req.iso_packets {
+                    UsbIpCodec::encode_iso_packet(packet, buf);
+                }
+
+                self.pending.insert(req.seqnum, PendingRequest::Submit(req.devid, req.ep));
+            },
+            Request::Unlink(req) => {
+                buf.reserve(UsbIpCodec::PDU_LENGTH);
+
+                buf.put_u32(OP_CMD_UNLINK);
+
+                UsbIpCodec::encode_urb_header(req.seqnum, req.devid, req.ep, buf);
+                buf.put_u32(req.unlink_seqnum);
+
+                // Pad to the full PDU length
+                buf.put_slice(&[0u8; UsbIpCodec::PDU_LENGTH - (4 + UsbIpCodec::URB_HEADER_SIZE + 4)]);
+
+                self.pending.insert(
+                    req.seqnum,
+                    PendingRequest::Unlink(req.devid, req.ep, req.unlink_seqnum));
             },
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn submit(direction: u32, transfer_buffer_length: u32, data: &[u8], iso_packets: &[(u32, u32)])
+        -> BytesMut
+    {
+        let mut buf = BytesMut::new();
+
+        buf.put_u32(OP_CMD_SUBMIT);
+        buf.put_u32(1); // seqnum
+        buf.put_u32(0x0001_0001); // devid
+        buf.put_u32(direction);
+        buf.put_u32(2); // ep
+        buf.put_u32(0); // transfer_flags
+        buf.put_u32(transfer_buffer_length);
+        buf.put_u32(0); // start_frame
+        buf.put_u32(if iso_packets.is_empty() { 0xffff_ffff } else { iso_packets.len() as u32 });
+        buf.put_u32(0); // interval
+        buf.put_slice(&[0u8; 8]); // setup
+        buf.put_slice(data);
+
+        for &(offset, length) in iso_packets {
+            buf.put_u32(offset);
+            buf.put_u32(length);
+            buf.put_u32(0); // actual_length
+            buf.put_i32(0); // status
+        }
+
+        buf
+    }
+
+    fn expect_protocol_error<T: fmt::Debug>(res: Result<Option<T>, Error>, what: &str) {
+        match res {
+            Err(Error::Protocol(msg)) => assert!(msg.contains(what), "unexpected message: {}", msg),
+            res => panic!("expected a protocol error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn decode_submit_one_byte_at_a_time() {
+        let frame = submit(0, 8, &[1, 2, 3, 4, 5, 6, 7, 8], &[(0, 4), (4, 4)]);
+
+        let mut codec = UsbIpCodec::with_max_transfer_size(DEFAULT_MAX_TRANSFER_SIZE);
+        let mut src = BytesMut::new();
+
+        for (i, &b) in frame.iter().enumerate() {
+            src.put_u8(b);
+
+            let res = codec.decode(&mut src).unwrap();
+
+            if i + 1 < frame.len() {
+                assert!(res.is_none(), "decoded a request after {} bytes", i + 1);
+                continue;
+            }
+
+            match res {
+                Some(Request::Submit(req)) => {
+                    assert_eq!(req.seqnum, 1);
+                    assert_eq!(req.ep, EndpointAddress::from_parts(2, UsbDirection::Out));
+                    assert_eq!(req.transfer_buffer_length, 8);
+                    assert_eq!(&req.data[..], &[1, 2, 3, 4, 5, 6, 7, 8]);
+                    assert_eq!(req.iso_packets.len(), 2);
+                    assert_eq!(req.iso_packets[1].offset, 4);
+                    assert_eq!(req.iso_packets[1].length, 4);
+                },
+                res => panic!("expected a submit request, got {:?}", res),
+            }
+        }
+
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_back_to_back_requests() {
+        let mut src = submit(0, 2, &[1, 2], &[]);
+        src.put_u32(OP_REQ_DEVLIST);
+        src.put_u32(0);
+
+        let mut codec = UsbIpCodec::with_max_transfer_size(DEFAULT_MAX_TRANSFER_SIZE);
+
+        assert!(matches!(codec.decode(&mut src), Ok(Some(Request::Submit(_)))));
+        assert!(matches!(codec.decode(&mut src), Ok(Some(Request::DevList))));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_long_transfer() {
+        let mut src = submit(1, 4096, &[], &[]);
+
+        let mut codec = UsbIpCodec::with_max_transfer_size(1024);
+
+        expect_protocol_error(codec.decode(&mut src), "exceeds maximum");
+    }
+
+    #[test]
+    fn decode_rejects_bad_version() {
+        let mut src = BytesMut::new();
+        src.put_u32(0x0100_8005); // OP_REQ_DEVLIST for version 1.00
+        src.put_u32(0);
+
+        let mut codec = UsbIpCodec::with_max_transfer_size(DEFAULT_MAX_TRANSFER_SIZE);
+
+        expect_protocol_error(codec.decode(&mut src), "unsupported protocol version");
+    }
+
+    #[test]
+    fn decode_rejects_iso_packet_outside_buffer() {
+        let mut src = submit(0, 8, &[0; 8], &[(0, 4), (6, 4)]);
+
+        let mut codec = UsbIpCodec::with_max_transfer_size(DEFAULT_MAX_TRANSFER_SIZE);
+
+        expect_protocol_error(codec.decode(&mut src), "isochronous packet 1 outside");
+    }
+
+    #[test]
+    fn decode_rejects_huge_device_list() {
+        let mut src = BytesMut::new();
+        src.put_u32(OP_REP_DEVLIST);
+        src.put_u32(0); // status
+        src.put_u32(0xffff_ffff); // number of devices
+
+        let mut codec = HostCodec::with_max_transfer_size(64 * 1024);
+
+        expect_protocol_error(codec.decode(&mut src), "exceeds maximum");
+    }
+}
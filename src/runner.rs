@@ -0,0 +1,57 @@
+use crate::server::{Poller, PollWaker};
+
+/// Drives a virtual USB device by polling it only when there is work to do.
+///
+/// The runner owns whatever needs to be polled, typically the `UsbDevice` together with its
+/// classes, and wakes up whenever the host submits a URB, an IN packet has been accepted or a
+/// [`PollWaker`] is triggered.
+///
+/// ```ignore
+/// let mut runner = DeviceRunner::new(poller, (usb_dev, serial));
+///
+/// loop {
+///     let (usb_dev, serial) = runner.next().await;
+///
+///     if usb_dev.poll(serial).is_ok() {
+///         // read from or write to the class
+///     }
+/// }
+/// ```
+pub struct DeviceRunner<T> {
+    poller: Poller,
+    device: T,
+}
+
+impl<T> DeviceRunner<T> {
+    pub fn new(poller: Poller, device: T) -> Self {
+        DeviceRunner { poller, device }
+    }
+
+    /// Returns a handle for waking up the runner, for instance after writing to a class from
+    /// another task.
+    pub fn waker(&self) -> PollWaker {
+        self.poller.waker()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.device
+    }
+
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Waits until the device needs to be polled and returns it for polling.
+    pub async fn next(&mut self) -> &mut T {
+        self.poller.poll().await;
+
+        &mut self.device
+    }
+
+    /// Calls `poll` every time the device needs to be polled. Never returns.
+    pub async fn run<F: FnMut(&mut T)>(mut self, mut poll: F) {
+        loop {
+            poll(self.next().await);
+        }
+    }
+}
@@ -1,323 +1,1137 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::sync::{
-    Arc, Mutex,
+    Arc, Mutex, Weak,
     atomic::{
-        AtomicBool,
+        AtomicBool, AtomicU8, AtomicU16, AtomicU32,
         Ordering::SeqCst,
     }
 };
-use bytes::{Bytes, BytesMut, Buf};
-use futures::sink::SinkExt as _;
-use futures::stream::StreamExt as _;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, BoxFuture, FutureExt as _};
+use futures::sink::{Sink, SinkExt as _};
+use futures::stream::{Stream, StreamExt as _};
 //use futures_codec::Framed;
 //use tokio::prelude::*;
 //use tokio::stream::StreamExt as _;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio::sync::{mpsc, watch};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio_util::codec::Framed;
+use tracing::{Instrument as _, Span, debug, info, trace, warn};
 use usb_device::{
     UsbDirection,
     control,
     descriptor::descriptor_type,
     endpoint::EndpointAddress,
+    usbcore::PollResult,
 };
-use crate::usbcore::UsbCore;
+use crate::{Error, Speed};
+use crate::access::{self, AccessPolicy};
+use crate::descriptor::{self, DescriptorSource, DeviceDescriptors};
+use crate::lint;
+use crate::usbcore::{NUM_ENDPOINTS, UsbCore};
 use crate::protocol::*;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
 
 pub struct Server {
-    listener: TcpListener,
+    // Empty for a server that only serves streams passed to `client`
+    listeners: Vec<Listener>,
+    registry: Arc<Mutex<Registry>>,
+    max_transfer_size: usize,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
 }
 
 impl Server {
-    // TODO: Use ToSocketAddrs
-    pub async fn bind(addr: &str) -> io::Result<Server> {
-        let listener = TcpListener::bind(addr).await?;
+    /// Creates a server listening on TCP address `addr`, such as `"127.0.0.1:3240"` or
+    /// `"[::]:3240"`. See [`listen`](Self::listen) for how names resolving to more than one
+    /// address are handled.
+    ///
+    /// Whether binding to `[::]` accepts IPv4 connections as well depends on the system: it does
+    /// by default on Linux, but not on Windows or the BSDs. To be sure, bind a socket with
+    /// `IPV6_V6ONLY` set the way you want and pass it to [`listen_std`](Self::listen_std), or
+    /// listen on `0.0.0.0` separately where `[::]` is IPv6 only.
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Server, Error> {
+        let mut server = Server::new();
+
+        server.listen(addr).await?;
+
+        Ok(server)
+    }
+
+    /// Starts listening on TCP address `addr` in addition to any addresses the server is already
+    /// listening on.
+    ///
+    /// If `addr` resolves to more than one address, such as `"localhost:3240"` often does, the
+    /// server listens on every one of them that can be bound. Fails if none can.
+    pub async fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> Result<(), Error> {
+        let mut last_err = None;
+        let mut bound = 0;
+
+        for addr in tokio::net::lookup_host(addr).await? {
+            match TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    self.listeners.push(Listener::Tcp(listener));
+                    bound += 1;
+                },
+                Err(err) => {
+                    debug!(%addr, %err, "could not bind");
+                    last_err = Some(err);
+                },
+            }
+        }
+
+        if bound > 0 {
+            return Ok(());
+        }
+
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing"))
+            .into())
+    }
+
+    /// Starts accepting connections from an already bound listener, such as a socket passed in
+    /// by systemd with `LISTEN_FDS`. Must be called from within a Tokio runtime.
+    pub fn listen_std(&mut self, listener: std::net::TcpListener) -> Result<(), Error> {
+        // Inherited sockets are usually blocking
+        listener.set_nonblocking(true)?;
+
+        let listener = TcpListener::from_std(listener)?;
+
+        self.listeners.push(Listener::Tcp(listener));
+
+        Ok(())
+    }
+
+    /// Creates a server listening on a Unix domain socket at `path` that only the current user
+    /// can connect to. Use [`forward_unix`](crate::forward_unix) to make the socket reachable
+    /// for `usbip`, which only speaks TCP.
+    ///
+    /// A stale socket left at `path` by a previous server is removed first. Fails with
+    /// `AddrInUse` if another server is still listening on `path`. Any other kind of file at
+    /// `path` is left alone and makes binding fail.
+    #[cfg(unix)]
+    pub async fn bind_unix(path: impl AsRef<std::path::Path>) -> Result<Server, Error> {
+        Server::bind_unix_with_mode(path, 0o600).await
+    }
+
+    /// Like [`bind_unix`](Self::bind_unix), but sets the permissions of the socket to `mode`,
+    /// for instance 0o660 to let a group in as well.
+    ///
+    /// The permissions are set after the socket has been created, so for the socket never to be
+    /// accessible to others, put it in a directory only the intended users can access.
+    #[cfg(unix)]
+    pub async fn bind_unix_with_mode(path: impl AsRef<std::path::Path>, mode: u32) -> Result<Server, Error> {
+        use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+
+        let path = path.as_ref();
+
+        // The socket file outlives the listener, so remove the one from the last run. A socket
+        // that still accepts connections belongs to a running server and is left alone.
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => {
+                match std::os::unix::net::UnixStream::connect(path) {
+                    Ok(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("a server is already listening on {}", path.display())).into());
+                    },
+                    Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                        std::fs::remove_file(path)?;
+                    },
+                    Err(err) => return Err(err.into()),
+                }
+            },
+            Ok(_) => {},
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err.into()),
+        }
+
+        let listener = UnixListener::bind(path)?;
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+        let mut server = Server::new();
+
+        server.listeners.push(Listener::Unix(listener));
+
+        Ok(server)
+    }
+
+    /// Creates a server that doesn't listen for connections yet. Add addresses to listen on with
+    /// [`listen`](Self::listen), or serve connections established by other means with
+    /// [`client`](Self::client).
+    pub fn new() -> Server {
+        Server {
+            listeners: Vec::new(),
+            registry: Arc::new(Mutex::new(Registry::new())),
+            max_transfer_size: DEFAULT_MAX_TRANSFER_SIZE,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Returns the first TCP address the server is listening on. Fails if it isn't listening on
+    /// TCP.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.local_addrs()?.into_iter().next().ok_or_else(not_listening)
+    }
+
+    /// Returns all TCP addresses the server is listening on.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, Error> {
+        let mut addrs = Vec::new();
+
+        for listener in &self.listeners {
+            if let Listener::Tcp(listener) = listener {
+                addrs.push(listener.local_addr()?);
+            }
+        }
+
+        Ok(addrs)
+    }
+
+    /// Registers a new full speed virtual device that is exported on every connection under
+    /// `bus_id`.
+    pub fn attach(&mut self, bus_id: &str) -> Result<(UsbCore, Poller), Error> {
+        self.attach_with_speed(bus_id, Speed::Full)
+    }
+
+    /// Registers a new virtual device like [`attach`](Self::attach), reporting it to hosts as
+    /// running at `speed`. Endpoints are checked against it when they are allocated.
+    ///
+    /// High speed devices must have a 64 byte control endpoint, which isn't the default of
+    /// `UsbDeviceBuilder`, so set it with `.max_packet_size_0(64)` when building the device.
+    pub fn attach_with_speed(&mut self, bus_id: &str, speed: Speed) -> Result<(UsbCore, Poller), Error> {
+        self.registry.lock().unwrap().attach(bus_id, speed)
+    }
+
+    /// Reads all descriptors of the device attached as `bus_id`. The device must be polled for
+    /// this to complete.
+    pub async fn descriptors(&self, bus_id: &str) -> Result<DeviceDescriptors, Error> {
+        let core = self.registry.lock().unwrap().find(bus_id)
+            .ok_or_else(|| Error::Device(format!("no such device: {}", bus_id)))?;
+
+        let mut core = core.lock().await;
+
+        core.descriptors().await
+    }
+
+    /// Enables checking the descriptors of devices attached after this for conformance when they
+    /// are first enumerated. Issues are logged as warnings.
+    pub fn set_lint_descriptors(&mut self, enabled: bool) {
+        self.registry.lock().unwrap().lint_descriptors = enabled;
+    }
+
+    /// Sets the largest URB transfer buffer accepted from hosts on connections accepted after
+    /// this. A host that submits a larger URB is disconnected. The default is 16 MiB.
+    pub fn set_max_transfer_size(&mut self, max_transfer_size: usize) {
+        self.max_transfer_size = max_transfer_size;
+    }
+
+    /// Requires TLS on connections accepted after this. Hosts only see the devices their client
+    /// certificate has been allowed in `config`.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, config: TlsConfig) {
+        self.tls = Some(config);
+    }
 
-        Ok(Server { listener })
+    /// Restricts which devices hosts connected over TCP may list and import, from the next
+    /// request on.
+    pub fn set_access_policy(&mut self, policy: impl AccessPolicy + 'static) {
+        self.registry.lock().unwrap().policy = Some(Arc::new(policy));
     }
 
-    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
-        self.listener.local_addr()
+    /// Waits for a connection on any of the addresses the server is listening on.
+    pub async fn accept(&mut self) -> Result<Client, Error> {
+        if self.listeners.is_empty() {
+            return Err(not_listening());
+        }
+
+        let accepts = self.listeners.iter_mut().map(|l| l.accept().boxed());
+
+        let (res, _, _) = future::select_all(accepts).await;
+        let (stream, peer, peer_addr) = res?;
+
+        let mut client = self.client(stream, peer);
+
+        client.session.peer_addr = peer_addr;
+
+        Ok(client)
     }
 
-    pub async fn accept(&mut self) -> io::Result<Client> {
-        self.listener.accept().await.map(|(stream, _)| Client::new(stream))
+    /// Serves devices over a connection that was established by other means than
+    /// [`accept`](Self::accept), such as a Unix domain socket or a serial line. `peer` describes
+    /// the other end in logs.
+    pub fn client<S>(&self, stream: S, peer: impl fmt::Display) -> Client<S>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let client = Client::new(stream, peer.to_string(), Arc::clone(&self.registry), self.max_transfer_size);
+
+        #[cfg(feature = "tls")]
+        let client = Client { tls: self.tls.clone(), ..client };
+
+        client
     }
 }
 
-pub struct Client {
-    stream: TcpStream,
-    next_devid: u32,
-    cores: HashMap<u32, ClientCore>,
-    complete_sender: mpsc::UnboundedSender<Urb>,
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Returns the connection, a description of the peer for logs, and the address of the peer
+    /// if it has one.
+    async fn accept(&mut self) -> io::Result<(Connection, String, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+
+                Ok((Connection::Tcp(stream), peer.to_string(), Some(peer)))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+
+                // Unix domain socket peers rarely have an address, but they do have a user
+                let peer = match stream.peer_cred() {
+                    Ok(cred) => format!("unix:uid={}", cred.uid),
+                    Err(_) => "unix".into(),
+                };
+
+                Ok((Connection::Unix(stream), peer, None))
+            },
+        }
+    }
+}
+
+/// A connection accepted by a [`Server`].
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+fn not_listening() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::NotConnected, "server is not listening"))
+}
+
+// All virtual devices live on a single virtual bus
+const BUSNUM: u32 = 1;
+
+// Maximum length of a bus ID, not including the NUL terminator
+const MAX_BUS_ID_LEN: usize = 31;
+
+// How long a device may take to answer a control transfer made by the server itself, such as
+// during enumeration. A device that isn't being polled must not hang the connections asking
+// about it.
+const CONTROL_TRANSFER_TIMEOUT: Duration = Duration::from_secs(2);
+
+type SharedDeviceCore = Arc<tokio::sync::Mutex<DeviceCore>>;
+
+struct RegisteredDevice {
+    bus_id: String,
+    core: SharedDeviceCore,
+}
+
+/// Devices exported by a server, shared by all of its connections.
+struct Registry {
+    next_devnum: u32,
+    next_client_id: u64,
+    devices: BTreeMap<u32, RegisteredDevice>,
+    lint_descriptors: bool,
+    policy: Option<Arc<dyn AccessPolicy>>,
+    // Number of devices imported from each IP address, counted against the policy's limit
+    imports: HashMap<IpAddr, usize>,
+}
+
+impl Registry {
+    fn new() -> Self {
+        Registry {
+            next_devnum: 1,
+            next_client_id: 1,
+            devices: BTreeMap::new(),
+            lint_descriptors: false,
+            policy: None,
+            imports: HashMap::new(),
+        }
+    }
+
+    fn next_client_id(&mut self) -> u64 {
+        let id = self.next_client_id;
+        self.next_client_id += 1;
+        id
+    }
+
+    fn attach(&mut self, bus_id: &str, speed: Speed) -> Result<(UsbCore, Poller), Error> {
+        if bus_id.is_empty() || bus_id.len() > MAX_BUS_ID_LEN {
+            return Err(Error::Device(format!("invalid bus ID: {:?}", bus_id)));
+        }
+
+        if self.find(bus_id).is_some() {
+            return Err(Error::Device(format!("bus ID already in use: {}", bus_id)));
+        }
+
+        let devnum = self.next_devnum;
+        self.next_devnum += 1;
+
+        // USB/IP identifies devices by bus and device number
+        let devid = (BUSNUM << 16) | devnum;
+
+        let (dcore, poller) = DeviceCore::new(devid, bus_id, speed, self.lint_descriptors);
+
+        let usbcore = UsbCore::new(dcore.channel.clone());
+
+        self.devices.insert(devid, RegisteredDevice {
+            bus_id: bus_id.to_owned(),
+            core: Arc::new(tokio::sync::Mutex::new(dcore)),
+        });
+
+        Ok((usbcore, poller))
+    }
+
+    fn find(&self, bus_id: &str) -> Option<SharedDeviceCore> {
+        self.devices.values()
+            .find(|d| d.bus_id == bus_id)
+            .map(|d| Arc::clone(&d.core))
+    }
+
+    fn devices(&self) -> Vec<(String, SharedDeviceCore)> {
+        self.devices.values().map(|d| (d.bus_id.clone(), Arc::clone(&d.core))).collect()
+    }
+}
+
+/// A connection to a host. Any bidirectional byte stream will do.
+pub struct Client<S = Connection> {
+    stream: S,
+    peer: String,
+    max_transfer_size: usize,
+    session: Session,
     complete_receiver: mpsc::UnboundedReceiver<Urb>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
 }
 
-impl Client {
-    fn new(stream: TcpStream) -> Self {
-        let (complete_sender, complete_receiver) = mpsc::unbounded_channel();
+impl<S: AsyncRead + AsyncWrite + Send + 'static> Client<S> {
+    fn new(
+        stream: S,
+        peer: String,
+        registry: Arc<Mutex<Registry>>,
+        max_transfer_size: usize) -> Self
+    {
+        let (session, complete_receiver) = Session::new(registry);
 
         Client {
             stream,
-            next_devid: 1,
-            cores: HashMap::new(),
-            complete_sender,
+            peer,
+            max_transfer_size,
+            session,
             complete_receiver,
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
-    pub fn attach(&mut self, bus_id: &str) -> (UsbCore, Poller) {
-        //let devid = self.next_devid;
-        let devid = 65537; // ????
-        //self.next_devid += 1;
+    pub async fn run(mut self) -> Result<(), Error> {
+        let span = tracing::info_span!("connection", id = self.session.id, peer = %self.peer);
+
+        let codec = UsbIpCodec::with_max_transfer_size(self.max_transfer_size);
+
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = self.tls.take() {
+                // rustls wants an Unpin stream
+                let (stream, bus_ids) = match tls.accept(Box::pin(self.stream)).await {
+                    Ok(res) => res,
+                    Err(err) => {
+                        span.in_scope(|| warn!(%err, "TLS handshake failed"));
+
+                        return Err(err.into());
+                    },
+                };
 
-        let (ccore, poller) = ClientCore::new(devid, bus_id, self.complete_sender.clone());
+                span.in_scope(|| debug!(?bus_ids, "TLS client authenticated"));
 
-        let usbcore = UsbCore::new(ccore.channel.clone());
+                self.session.visible = Some(bus_ids);
 
-        self.cores.insert(devid, ccore);
+                return self.session.run(stream, codec, self.complete_receiver, span).await;
+            }
+        }
 
-        (usbcore, poller)
+        self.session.run(self.stream, codec, self.complete_receiver, span).await
     }
+}
 
-    pub async fn run(mut self) -> io::Result<()> {
-        let (sink, mut stream) = Framed::new(self.stream, UsbIpCodec::new()).split();
-        let sink = Arc::new(tokio::sync::Mutex::new(sink));
+/// Creates a device that is served over an in-memory connection instead of a socket, and returns
+/// the host end of the connection. Must be called from within a Tokio runtime.
+pub(crate) fn loopback(bus_id: &str, speed: Speed) -> Result<(UsbCore, Poller, DuplexStream), Error> {
+    let registry = Arc::new(Mutex::new(Registry::new()));
 
-        let mut complete_receiver = self.complete_receiver;
+    let (usbcore, poller) = registry.lock().unwrap().attach(bus_id, speed)?;
 
-        let csink = Arc::clone(&sink);
+    let (host_stream, device_stream) = tokio::io::duplex(LOOPBACK_BUFFER_SIZE);
 
-        /*for core in self.cores.values_mut() {
-            core.enumerate().await.expect("enumeration failed");
-        }*/
+    let client = Client::new(device_stream, "loopback".into(), registry, DEFAULT_MAX_TRANSFER_SIZE);
+
+    tokio::spawn(client.run());
+
+    Ok((usbcore, poller, host_stream))
+}
+
+const LOOPBACK_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Per-connection state.
+struct Session {
+    id: u64,
+    registry: Arc<Mutex<Registry>>,
+    imported: HashMap<u32, SharedDeviceCore>,
+    complete_sender: mpsc::UnboundedSender<Urb>,
+    // Bus IDs the peer may list and import, if it is restricted to some
+    visible: Option<Arc<HashSet<String>>>,
+    // Address of the peer for the access policy, if it is connected over TCP
+    peer_addr: Option<SocketAddr>,
+    // Imports counted against the peer's limit in the registry
+    counted_imports: usize,
+}
+
+impl Session {
+    fn new(registry: Arc<Mutex<Registry>>) -> (Self, mpsc::UnboundedReceiver<Urb>) {
+        let (complete_sender, complete_receiver) = mpsc::unbounded_channel();
+
+        let id = registry.lock().unwrap().next_client_id();
+
+        let session = Session {
+            id,
+            registry,
+            imported: HashMap::new(),
+            complete_sender,
+            visible: None,
+            peer_addr: None,
+            counted_imports: 0,
+        };
+
+        (session, complete_receiver)
+    }
+
+    fn is_visible(&self, bus_id: &str) -> bool {
+        if !self.visible.as_ref().map(|v| v.contains(bus_id)).unwrap_or(true) {
+            return false;
+        }
+
+        let policy = self.registry.lock().unwrap().policy.clone();
+
+        match (self.peer_addr, policy) {
+            (Some(peer), Some(policy)) => policy.can_list(&peer, bus_id),
+            _ => true,
+        }
+    }
+
+    /// Checks that the access policy lets the peer import `bus_id`, and if so, counts the import
+    /// against the peer's limit.
+    fn acquire_import(&mut self, bus_id: &str) -> bool {
+        let peer = match self.peer_addr {
+            Some(peer) => peer,
+            None => return true,
+        };
+
+        let mut registry = self.registry.lock().unwrap();
+
+        let policy = match &registry.policy {
+            Some(policy) => Arc::clone(policy),
+            None => return true,
+        };
+
+        if !policy.can_import(&peer, bus_id) {
+            return false;
+        }
+
+        let count = registry.imports.entry(access::canonical_ip(peer.ip())).or_insert(0);
+
+        if policy.max_imports(&peer).map(|max| *count >= max).unwrap_or(false) {
+            return false;
+        }
+
+        *count += 1;
+        self.counted_imports += 1;
+
+        true
+    }
+
+    fn release_imports(&mut self, n: usize) {
+        let peer = match self.peer_addr {
+            Some(peer) if n > 0 => peer,
+            _ => return,
+        };
+
+        let ip = access::canonical_ip(peer.ip());
+
+        let mut registry = self.registry.lock().unwrap();
+
+        if let Some(count) = registry.imports.get_mut(&ip) {
+            *count = count.saturating_sub(n);
+
+            if *count == 0 {
+                registry.imports.remove(&ip);
+            }
+        }
+
+        self.counted_imports -= n;
+    }
+
+    /// Serves requests from a connection until it is closed.
+    async fn run<S>(
+        mut self,
+        stream: S,
+        codec: UsbIpCodec,
+        mut complete_receiver: mpsc::UnboundedReceiver<Urb>,
+        span: Span) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (sink, mut stream) = Framed::new(stream, codec).split();
+        let sink = Arc::new(tokio::sync::Mutex::new(sink));
+
+        let csink = Arc::clone(&sink);
 
         tokio::spawn(async move {
             while let Some(urb) = complete_receiver.recv().await {
-                if urb.internal {
-                    println!("completed internal urb: {:?}", urb);
-                    continue;
+                let res = csink.lock().await.send(Response::Submit(urb.into_response())).await;
+
+                if res.is_err() {
+                    // The connection is gone, and the request loop will notice it too
+                    break;
                 }
+            }
+        }.instrument(span.clone()));
+
+        async move {
+            info!("connected");
+
+            let res = self.serve(&mut stream, &sink).await;
 
-                csink.lock().await.send(
-                    Response::Submit(
-                        SubmitResponse {
-                            seqnum: urb.seqnum,
-                            devid: urb.devid,
-                            ep: urb.req_ep,
-                            status: 0, // OK
-                            actual_length: urb.data.len() as u32,
-                            actual_start_frame: 0,
-                            number_of_packets: 0,
-                            error_count: 0,
-                            setup: None,
-                            data: urb.data,
-                        })).await.expect("send failed");
+            match &res {
+                Ok(()) => info!("disconnected"),
+                Err(err) => warn!(%err, "connection failed"),
             }
-        });
 
-        /*let mut devices = Vec::new();
-        for core in self.cores.values_mut() {
-            devices.push(core.enumerate().await);
-        }*/
+            // Make the devices imported over this connection available to others again
+            self.close().await;
+
+            res
+        }.instrument(span).await
+    }
 
+    async fn serve<St, Si>(&mut self, stream: &mut St, sink: &tokio::sync::Mutex<Si>)
+        -> Result<(), Error>
+    where
+        St: Stream<Item = Result<Request, Error>> + Unpin,
+        Si: Sink<Response, Error = Error> + Unpin,
+    {
         while let Some(packet) = stream.next().await {
-            let packet = packet?;
+            self.handle_request(packet?, sink).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_request<Si>(&mut self, packet: Request, sink: &tokio::sync::Mutex<Si>)
+        -> Result<(), Error>
+    where
+        Si: Sink<Response, Error = Error> + Unpin,
+    {
+        match packet {
+            Request::DevList => {
+                debug!("device list requested");
 
-            match packet {
-                Request::DevList => {
-                    let mut devices = Vec::new();
+                let mut devices = Vec::new();
 
-                    for core in self.cores.values_mut() {
-                        devices.push(core.enumerate().await.expect("enumeration failed"));
+                let cores = self.registry.lock().unwrap().devices();
+                for (bus_id, core) in cores {
+                    if !self.is_visible(&bus_id) {
+                        continue;
                     }
 
-                    sink.lock().await.send(Response::DevList(devices)).await?;
-                },
-                Request::Import(bus_id) => {
-                    println!("IMPORT {}", bus_id);
-
-                    match self.cores.values_mut().find(|c| c.bus_id == bus_id) {
-                        Some(core) => {
-                            let info = core.enumerate().await.expect("enumeration failed");
-
-                            sink.lock().await.send(
-                                Response::Import(
-                                    ImportResponse {
-                                        status: 0, // OK
-                                        device: Some(Arc::clone(&info.device)),
-                                    })).await.expect("send failed");
-                        },
-                        None => {
-                            sink.lock().await.send(
-                                Response::Import(
-                                    ImportResponse {
-                                        status: 1, // ERROR
-                                        device: None,
-                                    })).await.expect("send failed");
-                        },
+                    // Devices that fail to enumerate are left out of the list
+                    match core.lock().await.enumerate().await {
+                        Ok(info) => devices.push(info),
+                        Err(err) => warn!(%err, "enumeration failed"),
+                    }
+                }
+
+                sink.lock().await.send(Response::DevList(devices)).await?;
+            },
+            Request::Import(bus_id) => {
+                // Devices the peer may not see look like they don't exist
+                let found = if self.is_visible(&bus_id) {
+                    self.registry.lock().unwrap().find(&bus_id)
+                } else {
+                    None
+                };
+
+                let (status, device) = match found {
+                    Some(shared_core) => {
+                        let mut core = shared_core.lock().await;
+
+                        match core.owner {
+                            Some(owner) if owner != self.id => (OpStatus::DeviceBusy, None),
+                            None if !self.acquire_import(&bus_id) => {
+                                info!(%bus_id, "import denied by access policy");
+
+                                (OpStatus::NotAvailable, None)
+                            },
+                            owner => match core.enumerate().await {
+                                Ok(info) => {
+                                    // Route completed URBs to this connection from now on
+                                    core.owner = Some(self.id);
+                                    core.bind(self.complete_sender.clone());
+
+                                    // Attaching to a new host looks like a bus reset to the device
+                                    core.request_reset();
+
+                                    self.imported.insert(core.devid, Arc::clone(&shared_core));
+
+                                    (OpStatus::Ok, Some(Arc::clone(&info.device)))
+                                },
+                                Err(err) => {
+                                    warn!(%err, "enumeration failed");
+
+                                    if owner.is_none() {
+                                        self.release_imports(1);
+                                    }
+
+                                    (OpStatus::DeviceError, None)
+                                },
+                            },
+                        }
+                    },
+                    None => (OpStatus::NoDevice, None),
+                };
+
+                info!(%bus_id, ?status, "import");
+
+                sink.lock().await.send(
+                    Response::Import(
+                        ImportResponse {
+                            status,
+                            device,
+                        })).await?;
+            },
+            Request::Submit(req) => {
+                if let Some(core) = self.imported.get(&req.devid) {
+                    let control = req.setup.map(|setup| UrbControl {
+                        setup,
+                        state: ControlState::Setup,
+                    });
+
+                    let ep = if control.is_some() {
+                        EndpointAddress::from_parts(req.ep.number(), UsbDirection::Out)
+                    } else {
+                        req.ep
                     };
-                    // TODO
-                },
-                Request::Submit(req) => {
-                    if let Some(core) = self.cores.values_mut().find(|c| c.devid == req.devid) {
-                        let control = req.setup.map(|setup| UrbControl {
-                            setup,
-                            state: ControlState::Setup,
-                        });
-
-                        let ep = if control.is_some() {
-                            EndpointAddress::from_parts(0, UsbDirection::Out)
-                        } else {
-                            req.ep
-                        };
-
-                        core.submit_urb(Urb {
-                            seqnum: req.seqnum,
-                            devid: req.devid,
-                            ep,
-                            req_ep: req.ep,
-                            control,
-                            len: req.transfer_buffer_length as usize,
-                            data: req.data,
-                            internal: false,
-                        });
+
+                    // The direction in the header is what counts. Like the Linux stub driver, fix up
+                    // the flag for hosts that don't keep it in sync. Control transfers without a
+                    // data stage are OUT even if the request itself is IN, so leave those alone.
+                    let transfer_flags = match (req.setup, req.ep.direction()) {
+                        (Some(_), _) => req.transfer_flags,
+                        (None, UsbDirection::In) => req.transfer_flags | URB_DIR_IN,
+                        (None, UsbDirection::Out) => req.transfer_flags & !URB_DIR_IN,
+                    };
+
+                    let iso = if req.iso_packets.is_empty() {
+                        None
                     } else {
-                        sink.lock().await.send(
-                            Response::Submit(
-                                SubmitResponse {
-                                    seqnum: req.seqnum,
-                                    devid: req.devid,
-                                    ep: req.ep,
-                                    status: 1, // ERROR
-                                    actual_length: 0,
-                                    actual_start_frame: 0,
-                                    number_of_packets: 0,
-                                    error_count: 0,
-                                    setup: None,
-                                    data: BytesMut::new(),
-                                })).await.expect("send failed");
-                    }
-                },
-                Request::Unlink(req) => {
-                    let success = self.cores.values_mut()
-                        .find(|c| c.devid == req.devid)
-                        .map(|c| c.unlink_urb(req.unlink_seqnum))
-                        .unwrap_or(false);
+                        Some(UrbIso {
+                            start_frame: req.start_frame,
+                            packets: req.iso_packets,
+                            next_packet: 0,
+                        })
+                    };
 
+                    core.lock().await.submit_urb(Urb {
+                        seqnum: req.seqnum,
+                        devid: req.devid,
+                        ep,
+                        req_ep: req.ep,
+                        control,
+                        iso,
+                        len: req.transfer_buffer_length as usize,
+                        transfer_flags,
+                        data: req.data,
+                        status: ResponseStatus::Ok,
+                        internal: false,
+                        state: Arc::default(),
+                        generation: 0,
+                        submitted: Instant::now(),
+                        span: Span::none(),
+                    });
+                } else {
                     sink.lock().await.send(
-                        Response::Unlink(
-                            UnlinkResponse {
+                        Response::Submit(
+                            SubmitResponse {
                                 seqnum: req.seqnum,
                                 devid: req.devid,
                                 ep: req.ep,
-                                status: if success { 1 } else { 0 },
-                                unlink_seqnum: req.unlink_seqnum,
-                            })).await.expect("send failed");
+                                status: ResponseStatus::NoDevice.code(),
+                                actual_length: 0,
+                                actual_start_frame: 0,
+                                number_of_packets: 0,
+                                error_count: 0,
+                                setup: None,
+                                data: BytesMut::new(),
+                                iso_packets: Vec::new(),
+                            })).await?;
+                }
+            },
+            Request::Unlink(req) => {
+                let success = match self.imported.get(&req.devid) {
+                    Some(core) => core.lock().await.unlink_urb(req.unlink_seqnum),
+                    None => false,
+                };
+
+                sink.lock().await.send(
+                    Response::Unlink(
+                        UnlinkResponse {
+                            seqnum: req.seqnum,
+                            devid: req.devid,
+                            ep: req.ep,
+                            status: if success { ResponseStatus::Unlinked.code() } else { 0 },
+                            unlink_seqnum: req.unlink_seqnum,
+                        })).await?;
+            },
+        }
+
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        for (_, core) in self.imported.drain() {
+            core.lock().await.release(self.id);
+        }
+
+        self.release_imports(self.counted_imports);
+    }
+}
+
+impl Drop for Session {
+    // The future serving the connection may be dropped before it gets to close the session, for
+    // instance by a timeout or when the runtime shuts down. Without this the devices would stay
+    // imported by a connection that no longer exists.
+    fn drop(&mut self) {
+        for (_, core) in self.imported.drain() {
+            let id = self.id;
+
+            let released = match core.try_lock() {
+                Ok(mut core) => {
+                    core.release(id);
+                    true
                 },
-                _ => { }
+                Err(_) => false,
+            };
+
+            // Another connection is using the device, so wait for it in the background
+            if !released {
+                if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                    handle.spawn(async move {
+                        core.lock().await.release(id);
+                    });
+                }
             }
-
-            //self.request_poll_sender.send(()).unwrap();
         }
 
-        Ok(())
+        self.release_imports(self.counted_imports);
     }
 }
 
-pub struct Poller(watch::Receiver<()>);
+/// Notifies the task driving a device whenever the device needs to be polled.
+pub struct Poller {
+    receiver: watch::Receiver<()>,
+    sender: Weak<watch::Sender<()>>,
+}
 
 impl Poller {
+    /// Waits until the device has work to do, such as a newly submitted URB or a completed IN
+    /// packet. Notifications are coalesced, and the first call returns immediately.
     pub async fn poll(&mut self) {
-        self.0.recv().await;
+        self.receiver.recv().await;
+    }
+
+    /// Returns a handle that can be used to wake up the poller from elsewhere, for instance after
+    /// the application has written data to a class from another task.
+    pub fn waker(&self) -> PollWaker {
+        PollWaker(Weak::clone(&self.sender))
+    }
+}
+
+/// Handle for waking up a [`Poller`]. Does not keep the device alive.
+#[derive(Clone)]
+pub struct PollWaker(Weak<watch::Sender<()>>);
+
+impl PollWaker {
+    pub fn wake(&self) {
+        if let Some(sender) = self.0.upgrade() {
+            sender.broadcast(()).ok();
+        }
     }
 }
 
-pub struct ClientCore {
+pub struct DeviceCore {
     devid: u32,
     bus_id: String,
-    urb_queue: Arc<Mutex<VecDeque<Urb>>>,
-    poll_sender: watch::Sender<()>,
+    // Connection that has imported the device
+    owner: Option<u64>,
+    urb_queues: Arc<UrbQueues>,
     channel: CoreChannel,
     info: Option<Arc<DeviceInterfaceInfo>>,
+    // Whether to check descriptors when the device is first enumerated
+    lint: bool,
+    // Sequence number for the next internal URB
+    next_internal_seqnum: u32,
+    // Host URBs that may still be in flight, for unlinking. Completed URBs are pruned on submit.
+    in_flight: HashMap<u32, Arc<UrbState>>,
+    span: Span,
 }
 
-impl ClientCore {
-    pub fn new(devid: u32, bus_id: &str, complete_sender: mpsc::UnboundedSender<Urb>)
-        -> (Self, Poller)
+impl DeviceCore {
+    pub fn new(devid: u32, bus_id: &str, speed: Speed, lint: bool) -> (Self, Poller)
     {
         let (poll_sender, poll_receiver) = watch::channel(());
+        let poll_sender = Arc::new(poll_sender);
 
-        let urb_queue = Arc::new(Mutex::new(VecDeque::new()));
+        let urb_queues = Arc::new(UrbQueues::default());
 
         (
-            ClientCore {
+            DeviceCore {
                 devid,
                 bus_id: bus_id.to_owned(),
-                urb_queue: Arc::clone(&urb_queue),
-                poll_sender,
+                owner: None,
+                urb_queues: Arc::clone(&urb_queues),
                 info: None,
+                lint,
+                next_internal_seqnum: 1,
+                in_flight: HashMap::new(),
+                span: tracing::info_span!(parent: None, "device", %bus_id, devid),
                 channel: CoreChannel {
-                    urb_queue,
-                    complete_sender,
+                    urb_queues,
+                    complete_sender: Arc::new(Mutex::new(None)),
                     internal_complete_sender: Arc::new(Mutex::new(None)),
-                    control_in_progress: Arc::new(AtomicBool::new(false)),
+                    poll_sender: Arc::clone(&poll_sender),
+                    control_active: Arc::new(AtomicU16::new(0)),
+                    stalled: Arc::new(AtomicU32::new(0)),
+                    out_pending: Arc::new(AtomicU16::new(0)),
+                    in_complete: Arc::new(AtomicU16::new(0)),
+                    in_waiting: Arc::new(AtomicU16::new(0)),
+                    reset_pending: Arc::new(AtomicBool::new(false)),
+                    generation: Arc::new(AtomicU32::new(0)),
+                    speed,
                 }
             },
-            Poller(poll_receiver),
+            Poller {
+                receiver: poll_receiver,
+                sender: Arc::downgrade(&poll_sender),
+            },
         )
     }
 
-    pub fn submit_urb(&mut self, urb: Urb) {
-        //println!("submit: {:?}", &urb);
+    pub fn submit_urb(&mut self, mut urb: Urb) {
+        urb.generation = self.channel.generation.load(SeqCst);
+        urb.submitted = Instant::now();
+        urb.span = tracing::debug_span!(
+            parent: &self.span,
+            "urb",
+            seqnum = urb.seqnum,
+            ep = ?urb.req_ep,
+            internal = urb.internal);
+
+        match &urb.control {
+            Some(control) => urb.span.in_scope(|| debug!(
+                len = urb.len,
+                setup = %DisplaySetup(&control.setup),
+                "submitted")),
+            None => urb.span.in_scope(|| debug!(len = urb.len, "submitted")),
+        }
+
+        if !urb.internal {
+            self.in_flight.retain(|_, state| state.is_pending());
+            self.in_flight.insert(urb.seqnum, Arc::clone(&urb.state));
+        }
 
         // Control transfers must always first be directed to the control OUT endpoint for SETUP
         /*if urb.is_control {
             urb.ep = EndpointAddress::from_parts(urb.req_ep.number(), UsbDirection::Out);
         }*/
 
-        self.urb_queue.lock().unwrap().push_back(urb);
-        self.poll_sender.broadcast(()).expect("poll send failed");
+        if urb.control.is_none() && self.channel.is_stalled(urb.ep) {
+            // Transfers to a halted endpoint fail until the host clears the halt
+            self.channel.stall_urb(urb);
+            return;
+        }
+
+        if urb.control.is_none() && urb.ep.direction() == UsbDirection::In {
+            // Let the class know it can now write to an endpoint it previously found blocked
+            self.channel.notify_in_ready(urb.ep);
+        }
+
+        self.urb_queues.push_back(urb);
+        self.channel.wake();
+    }
+
+    /// Sends completed URBs to `complete_sender` from now on.
+    pub fn bind(&mut self, complete_sender: mpsc::UnboundedSender<Urb>) {
+        *self.channel.complete_sender.lock().unwrap() = Some(complete_sender);
     }
 
+    /// Gives up ownership of the device if it is currently imported by connection `owner`. All
+    /// URBs submitted by the connection are cancelled and the device is reset as if it had been
+    /// unplugged.
+    pub fn release(&mut self, owner: u64) {
+        if self.owner != Some(owner) {
+            return;
+        }
+
+        self.owner = None;
+        *self.channel.complete_sender.lock().unwrap() = None;
+
+        // URBs that have already been taken by an endpoint are dropped when the endpoint next
+        // looks at them
+
+        self.channel.generation.fetch_add(1, SeqCst);
+
+        // Internal URBs are only submitted while the device is locked, so none can be in flight
+        self.urb_queues.clear();
+        self.in_flight.clear();
+        self.channel.control_active.store(0, SeqCst);
+
+        self.request_reset();
+    }
+
+    pub fn request_reset(&mut self) {
+        self.channel.reset_pending.store(true, SeqCst);
+        self.channel.wake();
+    }
+
+    /// Cancels a URB submitted by the host. Returns false if the URB has already completed, in
+    /// which case its RET_SUBMIT is sent as usual.
     pub fn unlink_urb(&mut self, seqnum: u32) -> bool {
-        let mut queue = self.urb_queue.lock().unwrap();
-        let len_before = queue.len();
+        let state = match self.in_flight.remove(&seqnum) {
+            Some(state) => state,
+            None => return false,
+        };
+
+        // Whichever comes first, the completion or the unlink, wins
+        if !state.cancel() {
+            return false;
+        }
+
+        let unlinked = self.urb_queues.remove(|u| Arc::ptr_eq(&u.state, &state));
 
-        queue.retain(|u| u.seqnum == seqnum);
+        // A URB that has already been taken by an endpoint is discarded when the endpoint next
+        // looks at it
+        if let Some(urb) = unlinked {
+            urb.span.in_scope(|| debug!("unlinked while queued"));
+
+            self.channel.discard_urb(urb);
+        } else {
+            self.span.in_scope(|| debug!(seqnum, "unlinked"));
+        }
 
-        queue.len() != len_before
+        true
     }
 
-    pub async fn enumerate(&mut self) -> Result<Arc<DeviceInterfaceInfo>, String> {
+    pub async fn enumerate(&mut self) -> Result<Arc<DeviceInterfaceInfo>, Error> {
         if let Some(info) = self.info.as_ref() {
             return Ok(Arc::clone(info));
         }
 
-        println!("get device descriptor");
-        let mut dev = self.get_descriptor(descriptor_type::DEVICE, 0, 18).await?;
-        println!("{:02x?}", dev);
+        let span = self.span.clone();
+
+        let info = self.enumerate_device().instrument(span.clone()).await?;
+
+        if self.lint {
+            self.lint_descriptors().instrument(span).await;
+        }
+
+        Ok(info)
+    }
+
+    async fn lint_descriptors(&mut self) {
+        let desc = match descriptor::read_descriptors(self).await {
+            Ok(desc) => desc,
+            Err(err) => {
+                warn!(%err, "reading descriptors for checking failed");
+                return;
+            },
+        };
 
-        if usize::from(dev.get_u8()) < 18 {
-            return Err("invalid device descriptor: length field too small".into());
+        let report = lint::lint(&desc, self.channel.speed);
+
+        for issue in &report.issues {
+            warn!(kind = ?issue.kind, "descriptor {}", issue);
         }
 
-        if dev.get_u8() != descriptor_type::DEVICE {
-            return Err("invalid device descriptor: incorrect descriptor type".into());
+        if report.issues.is_empty() {
+            debug!("descriptors passed checks");
         }
+    }
 
-        dev.advance(2); // bcdUSB
-        let device_class = dev.get_u8();
-        let device_subclass = dev.get_u8();
-        let device_protocol = dev.get_u8();
-        dev.advance(1); // bMaxPacketSize0
-        let id_vendor = dev.get_u16_le();
-        let id_product = dev.get_u16_le();
-        let bcd_device = dev.get_u16_le();
-        dev.advance(3); // iManufacturer, iProduct, iSerialNUmber
-        let num_configuration = dev.get_u8();
+    async fn enumerate_device(&mut self) -> Result<Arc<DeviceInterfaceInfo>, Error> {
+        let dev = self.get_descriptor(descriptor_type::DEVICE, 0, 0).await?;
+        debug!(descriptor = ?&dev[..], "device descriptor");
+
+        let dev = descriptor::parse_device(&dev)?;
 
         self.control_transfer(control::Request {
             direction: UsbDirection::Out,
@@ -329,72 +1143,37 @@ impl ClientCore {
             length: 0,
         }).await?;
 
-        println!("get configuration descriptor");
-        let mut config_all = self.get_descriptor(descriptor_type::CONFIGURATION, 0, 9).await?;
-        println!("{:02x?}", config_all);
-
-        let len = config_all.len();
-
-        let mut config = config_all.split_to(9);
-
-        if usize::from(config.get_u8()) < 9 {
-            return Err("invalid configuration descriptor: length field too small".into());
-        }
-
-        if config.get_u8() != descriptor_type::CONFIGURATION {
-            return Err("invalid configuration descriptor: incorrect descriptor type".into());
-        }
-
-        if usize::from(config.get_u16_le()) != len {
-            return Err("invalid configuration descriptor: wTotalLength mismatch".into());
-        }
-
-        let num_interfaces = config.get_u8();
-        let configuration_value = config.get_u8();
-
-        let mut interfaces = Vec::new();
-
-        while !config_all.is_empty() {
-            if config_all.len() < 2 {
-                return Err("invalid configuration descriptor: truncated".into());
-            }
-
-            let len = usize::from(config_all.get_u8());
-            let dtype = config_all.get_u8();
-
-            let mut desc = config_all.split_to(len - 2);
-
-            if dtype == descriptor_type::INTERFACE {
-                if desc.len() < 7 {
-                    return Err("invalid interface descriptor: too short".into());
-                }
+        let config = self.get_descriptor(descriptor_type::CONFIGURATION, 0, 0).await?;
+        debug!(descriptor = ?&config[..], "configuration descriptor");
 
-                desc.advance(3); // bInterfaceNumber, bAlternateSetting, bNumEndpoints
+        let config = descriptor::parse_configuration(&config)?;
 
-                interfaces.push(InterfaceInfo {
-                    interface_class: desc.get_u8(),
-                    interface_subclass: desc.get_u8(),
-                    interface_protocol: desc.get_u8(),
-                });
-            }
-        }
+        // Hosts expect one entry per interface, describing its default alternate setting
+        let interfaces = config.interfaces.iter()
+            .filter_map(|iface| iface.alt_settings.first())
+            .map(|alt| InterfaceInfo {
+                interface_class: alt.interface_class,
+                interface_subclass: alt.interface_subclass,
+                interface_protocol: alt.interface_protocol,
+            })
+            .collect::<Vec<_>>();
 
         let info = Arc::new(DeviceInterfaceInfo {
             device: Arc::new(DeviceInfo {
                 path: String::from("/virtual"),
                 busid: self.bus_id.clone(),
-                busnum: 1,
-                devnum: self.devid,
-                device_class,
-                device_subclass,
-                device_protocol,
-                speed: 2, // USB_SPEED_FULL
-                id_vendor,
-                id_product,
-                bcd_device,
-                configuration_value,
-                num_configuration,
-                num_interfaces,
+                busnum: self.devid >> 16,
+                devnum: self.devid & 0xffff,
+                device_class: dev.device_class,
+                device_subclass: dev.device_subclass,
+                device_protocol: dev.device_protocol,
+                speed: self.channel.speed as u32,
+                id_vendor: dev.vendor_id,
+                id_product: dev.product_id,
+                bcd_device: dev.device_version,
+                configuration_value: config.configuration_value,
+                num_configuration: dev.num_configurations,
+                num_interfaces: interfaces.len() as u8,
             }),
             interfaces,
         });
@@ -404,8 +1183,17 @@ impl ClientCore {
         Ok(info)
     }
 
-    async fn get_descriptor(&mut self, dtype: u8, dindex: u8, min_len: usize)
-        -> Result<Bytes, String>
+    /// Reads all descriptors of the device. The device is enumerated first if needed.
+    pub async fn descriptors(&mut self) -> Result<DeviceDescriptors, Error> {
+        self.enumerate().await?;
+
+        let span = self.span.clone();
+
+        descriptor::read_descriptors(self).instrument(span).await
+    }
+
+    async fn get_descriptor(&mut self, dtype: u8, dindex: u8, lang_id: u16)
+        -> Result<Bytes, Error>
     {
         let req = control::Request {
             direction: UsbDirection::In,
@@ -413,20 +1201,15 @@ impl ClientCore {
             recipient: control::Recipient::Device,
             request: control::Request::GET_DESCRIPTOR,
             value: (u16::from(dtype) << 8) | u16::from(dindex),
-            index: 0,
+            index: lang_id,
             length: 0xffff,
         };
 
-        let desc = self.control_transfer(req).await?;
-        if desc.len() < min_len {
-            return Err(format!("invalid {} descriptor: data length too short", dtype));
-        }
-
-        Ok(desc)
+        self.control_transfer(req).await
     }
 
     async fn control_transfer(&mut self, req: control::Request)
-        -> Result<Bytes, String>
+        -> Result<Bytes, Error>
     {
         let setup = [
             // bmRequestType
@@ -441,79 +1224,312 @@ impl ClientCore {
             req.length as u8, (req.length >> 8) as u8,
         ];
 
+        // The completion channel must be in place before submitting, as the device may be polled
+        // and complete the URB at any time after that.
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+
+        *self.channel.internal_complete_sender.lock().unwrap() = Some(sender);
+
+        let seqnum = self.next_internal_seqnum;
+        self.next_internal_seqnum = self.next_internal_seqnum.wrapping_add(1).max(1);
+
+        let state = Arc::new(UrbState::default());
+
         self.submit_urb(Urb {
-            seqnum: 0,
-            devid: 0,
+            seqnum,
+            devid: self.devid,
             ep: EndpointAddress::from_parts(0, UsbDirection::Out),
-            req_ep: EndpointAddress::from_parts(0, UsbDirection::In),
-            len: 0xffff,
+            req_ep: EndpointAddress::from_parts(0, req.direction),
+            len: req.length as usize,
             control: Some(
                 UrbControl {
                     setup,
                     state: ControlState::Setup,
                 }
             ),
+            iso: None,
+            transfer_flags: if req.direction == UsbDirection::In { URB_DIR_IN } else { 0 },
             data: BytesMut::new(),
+            status: ResponseStatus::Ok,
             internal: true,
+            state: Arc::clone(&state),
+            generation: 0,
+            submitted: Instant::now(),
+            span: Span::none(),
         });
 
-        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let res = tokio::time::timeout(CONTROL_TRANSFER_TIMEOUT, receiver.recv()).await;
 
-        *self.channel.internal_complete_sender.lock().unwrap() = Some(sender);
+        *self.channel.internal_complete_sender.lock().unwrap() = None;
 
-        let urb = receiver.recv().await.ok_or("recv failed")?;
+        let urb = match res {
+            Ok(Some(urb)) => urb,
+            Ok(None) => return Err(Error::Device("control transfer was cancelled".into())),
+            Err(_) => {
+                // A URB that has already been taken by an endpoint is discarded when the
+                // endpoint next looks at it, as it is no longer live
+                if state.cancel() {
+                    if let Some(urb) = self.urb_queues.remove(|u| Arc::ptr_eq(&u.state, &state)) {
+                        self.channel.discard_urb(urb);
+                    }
+                }
 
-        *self.channel.internal_complete_sender.lock().unwrap() = None;
+                warn!("control transfer timed out, is the device being polled?");
+
+                return Err(Error::Device("device did not respond to a control transfer".into()));
+            },
+        };
+
+        if urb.status != ResponseStatus::Ok {
+            return Err(Error::Transfer(urb.status.code()));
+        }
 
         Ok(urb.data.into())
     }
 }
 
+impl DescriptorSource for DeviceCore {
+    fn get_descriptor(&mut self, descriptor_type: u8, index: u8, lang_id: u16)
+        -> BoxFuture<'_, Result<Bytes, Error>>
+    {
+        DeviceCore::get_descriptor(self, descriptor_type, index, lang_id).boxed()
+    }
+}
+
 // The Arc/Mutex mess is probably backwards
 pub struct CoreChannel {
-    urb_queue: Arc<Mutex<VecDeque<Urb>>>,
-    complete_sender: mpsc::UnboundedSender<Urb>,
+    urb_queues: Arc<UrbQueues>,
+    // Connection that has imported the device, if any
+    complete_sender: Arc<Mutex<Option<mpsc::UnboundedSender<Urb>>>>,
     internal_complete_sender: Arc<Mutex<Option<mpsc::UnboundedSender<Urb>>>>,
-    // TODO: Make this per endpoint or something
-    control_in_progress: Arc<AtomicBool>,
+    poll_sender: Arc<watch::Sender<()>>,
+    // Control endpoints in the middle of a transfer, which won't take another SETUP packet until
+    // it has completed
+    control_active: Arc<AtomicU16>,
+    // Bit n is OUT endpoint n, bit 16 + n is IN endpoint n
+    stalled: Arc<AtomicU32>,
+    // OUT endpoints that are holding a partially read URB
+    out_pending: Arc<AtomicU16>,
+    // IN endpoints that have had a packet accepted since the last poll
+    in_complete: Arc<AtomicU16>,
+    // IN endpoints that have been written to while there was no URB to write into
+    in_waiting: Arc<AtomicU16>,
+    reset_pending: Arc<AtomicBool>,
+    // Incremented whenever the importing host disconnects
+    generation: Arc<AtomicU32>,
+    // Reported to the host and used to check endpoint configurations
+    speed: Speed,
 }
 
 impl CoreChannel {
+    fn ep_bit(ep_addr: EndpointAddress) -> u32 {
+        match ep_addr.direction() {
+            UsbDirection::Out => 1 << ep_addr.number(),
+            UsbDirection::In => 1 << (16 + ep_addr.number()),
+        }
+    }
+
+    pub fn poll(&mut self) -> PollResult {
+        if self.reset_pending.swap(false, SeqCst) {
+            // The reset may have been merged with a wake for URBs that arrived at the same time,
+            // so poll again to look at them once the device has handled the reset
+            self.wake();
+
+            return PollResult::Reset;
+        }
+
+        let ep_out = self.urb_queues.out_queued.load(SeqCst) | self.out_pending.load(SeqCst);
+
+        let ep_in_complete = self.in_complete.swap(0, SeqCst);
+
+        if ep_out == 0 && ep_in_complete == 0 {
+            return PollResult::None;
+        }
+
+        PollResult::Data {
+            ep_out,
+            ep_in_complete,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.stalled.store(0, SeqCst);
+        self.out_pending.store(0, SeqCst);
+        self.in_complete.store(0, SeqCst);
+        self.in_waiting.store(0, SeqCst);
+    }
+
+    /// Returns false if the URB has been unlinked or was submitted by a host that has since
+    /// disconnected.
+    pub fn is_live(&self, urb: &Urb) -> bool {
+        urb.generation == self.generation.load(SeqCst) && !urb.state.is_cancelled()
+    }
+
+    /// Drops a URB that is no longer live.
+    pub fn discard_urb(&mut self, urb: Urb) {
+        urb.span.in_scope(|| debug!("discarded"));
+
+        let in_progress = urb.control.as_ref()
+            .map(|c| c.state != ControlState::Setup)
+            .unwrap_or(false);
+
+        // A control transfer cancelled mid-way no longer blocks the next SETUP. After a
+        // disconnect the flags have already been reset.
+        if in_progress && urb.generation == self.generation.load(SeqCst) {
+            self.set_control_active(urb.ep, false);
+            self.wake();
+        }
+    }
+
+    fn set_control_active(&mut self, ep_addr: EndpointAddress, active: bool) {
+        if active {
+            self.control_active.fetch_or(1 << ep_addr.number(), SeqCst);
+        } else {
+            self.control_active.fetch_and(!(1 << ep_addr.number()), SeqCst);
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Wakes up the poller so that the device gets polled again.
+    pub fn wake(&self) {
+        self.poll_sender.broadcast(()).ok();
+    }
+
+    pub fn set_out_pending(&mut self, ep_addr: EndpointAddress, pending: bool) {
+        if pending {
+            self.out_pending.fetch_or(1 << ep_addr.number(), SeqCst);
+            self.wake();
+        } else {
+            self.out_pending.fetch_and(!(1 << ep_addr.number()), SeqCst);
+        }
+    }
+
+    pub fn set_in_complete(&mut self, ep_addr: EndpointAddress) {
+        self.in_complete.fetch_or(1 << ep_addr.number(), SeqCst);
+        self.wake();
+    }
+
+    pub fn set_in_waiting(&mut self, ep_addr: EndpointAddress) {
+        self.in_waiting.fetch_or(1 << ep_addr.number(), SeqCst);
+    }
+
+    fn notify_in_ready(&mut self, ep_addr: EndpointAddress) {
+        let bit = 1 << ep_addr.number();
+
+        if self.in_waiting.fetch_and(!bit, SeqCst) & bit != 0 {
+            self.in_complete.fetch_or(bit, SeqCst);
+        }
+    }
+
+    pub fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.stalled.load(SeqCst) & Self::ep_bit(ep_addr) != 0
+    }
+
+    pub fn set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        if !stalled {
+            self.stalled.fetch_and(!Self::ep_bit(ep_addr), SeqCst);
+            return;
+        }
+
+        self.stalled.fetch_or(Self::ep_bit(ep_addr), SeqCst);
+
+        // Fail everything that is waiting on the endpoint, except for SETUP packets which are
+        // always accepted by a control endpoint and clear the stall condition.
+
+        let stalled_urbs = self.urb_queues.with(ep_addr, |queue| {
+            let (stalled_urbs, rest): (Vec<Urb>, VecDeque<Urb>) = queue.drain(..)
+                .partition(|u| u.control.as_ref().map(|c| c.state != ControlState::Setup).unwrap_or(true));
+
+            *queue = rest;
+
+            stalled_urbs
+        });
+
+        for urb in stalled_urbs {
+            self.stall_urb(urb);
+        }
+    }
+
+    /// Completes the URB with an EPIPE status without passing it through any further stages.
+    pub fn stall_urb(&mut self, urb: Urb) {
+        self.fail_urb(urb, ResponseStatus::EndpointStalled);
+    }
+
+    /// Completes the URB with an error status without passing it through any further stages.
+    pub fn fail_urb(&mut self, mut urb: Urb, status: ResponseStatus) {
+        if urb.control.is_some() {
+            self.set_control_active(urb.ep, false);
+            self.wake();
+        }
+
+        urb.status = status;
+        urb.data.clear();
+
+        urb.span.in_scope(|| debug!(?status, "failed"));
+
+        self.send_complete(urb);
+    }
+
     pub fn take_next_urb(&mut self, ep_addr: EndpointAddress) -> Option<Urb> {
-        let mut queue = self.urb_queue.lock().unwrap();
+        let urb_queues = Arc::clone(&self.urb_queues);
 
-        match queue.iter()
-            .enumerate()
-            .find(|u| u.1.ep == ep_addr)
-        {
-            Some((index, urb)) => {
-                if let Some(control) = &urb.control {
-                    if self.control_in_progress.load(SeqCst) {
-                        if control.state == ControlState::Setup {
-                            return None;
-                        }
-                    } else {
-                        self.control_in_progress.store(true, SeqCst);
+        urb_queues.with(ep_addr, |queue| {
+            let urb = queue.front()?;
+
+            if let Some(control) = &urb.control {
+                let bit = 1 << ep_addr.number();
+
+                if self.control_active.load(SeqCst) & bit != 0 {
+                    if control.state == ControlState::Setup {
+                        return None;
                     }
+                } else {
+                    self.set_control_active(ep_addr, true);
                 }
 
-                queue.remove(index)
-            },
-            None => None,
-        }
+                if control.state == ControlState::Setup {
+                    // A SETUP packet clears a stall on both halves of the control endpoint
+                    self.stalled.fetch_and(
+                        !(Self::ep_bit(EndpointAddress::from_parts(ep_addr.number(), UsbDirection::Out))
+                            | Self::ep_bit(EndpointAddress::from_parts(ep_addr.number(), UsbDirection::In))),
+                        SeqCst);
+                }
+            }
+
+            urb.span.in_scope(|| trace!(ep = ?ep_addr, "taken by endpoint"));
+
+            queue.pop_front()
+        })
     }
 
     pub fn complete_urb(&mut self, mut urb: Urb) {
+        if !self.is_live(&urb) {
+            // Don't pass cancelled control URBs on to the next stage
+            self.discard_urb(urb);
+            return;
+        }
+
         if let Some(ref mut control) = urb.control {
             match control.state {
-                ControlState::Setup => panic!("Cannot complete_urb a SETUP"),
+                ControlState::Setup => {
+                    // Should not happen, but failing the transfer is better than hanging it
+                    self.fail_urb(urb, ResponseStatus::ProtocolError);
+                    return;
+                },
 
                 ControlState::Data => {
                     // OUT endpoint is passing to IN endpoint
 
                     urb.ep = EndpointAddress::from_parts(urb.ep.number(), UsbDirection::In);
 
-                    self.urb_queue.lock().unwrap().push_front(urb);
+                    urb.span.in_scope(|| trace!("data stage"));
+
+                    self.urb_queues.push_front(urb);
+                    self.wake();
                     return;
                 },
 
@@ -523,26 +1539,53 @@ impl CoreChannel {
                         UsbDirection::In => UsbDirection::Out,
                     };
 
-                    //println!("passing to {:?}", status_dir);
-
                     urb.ep = EndpointAddress::from_parts(urb.ep.number(), status_dir);
 
-                    self.urb_queue.lock().unwrap().push_front(urb);
+                    urb.span.in_scope(|| trace!(?status_dir, "status stage"));
+
+                    self.urb_queues.push_front(urb);
+                    self.wake();
                     return;
                 },
 
                 ControlState::Complete => {
                     /* handled below */
 
-                    self.control_in_progress.store(false, SeqCst);
+                    // The next SETUP packet may now be read
+                    self.set_control_active(urb.ep, false);
+                    self.wake();
                 }
             }
         }
 
-        if let Some(sender) = self.internal_complete_sender.lock().unwrap().take() {
-            sender.send(urb).unwrap();
+        self.send_complete(urb);
+    }
+
+    fn send_complete(&mut self, urb: Urb) {
+        if urb.generation != self.generation.load(SeqCst) {
+            return;
+        }
+
+        // An unlinked URB must not get a RET_SUBMIT
+        if !urb.state.complete() {
+            return;
+        }
+
+        let sender = if urb.internal {
+            self.internal_complete_sender.lock().unwrap().take()
         } else {
-            self.complete_sender.send(urb).unwrap();
+            self.complete_sender.lock().unwrap().clone()
+        };
+
+        urb.span.in_scope(|| debug!(
+            status = ?urb.status,
+            len = urb.data.len(),
+            elapsed_us = urb.submitted.elapsed().as_micros() as u64,
+            "completed"));
+
+        // URBs whose submitter has gone away are dropped
+        if let Some(sender) = sender {
+            sender.send(urb).ok();
         }
     }
 }
@@ -550,12 +1593,87 @@ impl CoreChannel {
 impl Clone for CoreChannel {
     fn clone(&self) -> CoreChannel {
         CoreChannel {
-            urb_queue: Arc::clone(&self.urb_queue),
-            complete_sender: self.complete_sender.clone(),
+            urb_queues: Arc::clone(&self.urb_queues),
+            complete_sender: Arc::clone(&self.complete_sender),
             internal_complete_sender: Arc::clone(&self.internal_complete_sender),
-            control_in_progress: Arc::clone(&self.control_in_progress),
+            poll_sender: Arc::clone(&self.poll_sender),
+            control_active: Arc::clone(&self.control_active),
+            stalled: Arc::clone(&self.stalled),
+            out_pending: Arc::clone(&self.out_pending),
+            in_complete: Arc::clone(&self.in_complete),
+            in_waiting: Arc::clone(&self.in_waiting),
+            reset_pending: Arc::clone(&self.reset_pending),
+            generation: Arc::clone(&self.generation),
+            speed: self.speed,
+        }
+    }
+}
+
+/// URBs waiting to be taken by an endpoint, in a queue per endpoint address. Control URBs move
+/// between the OUT and IN queues of their endpoint as they go through the stages.
+#[derive(Default)]
+struct UrbQueues {
+    out: [Mutex<VecDeque<Urb>>; NUM_ENDPOINTS],
+    in_: [Mutex<VecDeque<Urb>>; NUM_ENDPOINTS],
+    // OUT endpoints with URBs waiting, so that polling doesn't have to lock every queue
+    out_queued: AtomicU16,
+}
+
+impl UrbQueues {
+    /// Runs `f` with the queue of `ep_addr` locked.
+    fn with<R>(&self, ep_addr: EndpointAddress, f: impl FnOnce(&mut VecDeque<Urb>) -> R) -> R {
+        let number = usize::from(ep_addr.number());
+
+        match ep_addr.direction() {
+            UsbDirection::Out => {
+                let mut queue = self.out[number].lock().unwrap();
+
+                let res = f(&mut queue);
+
+                // Updated while the queue is still locked so that the bit can't go stale
+                if queue.is_empty() {
+                    self.out_queued.fetch_and(!(1 << number), SeqCst);
+                } else {
+                    self.out_queued.fetch_or(1 << number, SeqCst);
+                }
+
+                res
+            },
+            UsbDirection::In => f(&mut self.in_[number].lock().unwrap()),
+        }
+    }
+
+    fn push_back(&self, urb: Urb) {
+        self.with(urb.ep, |queue| queue.push_back(urb));
+    }
+
+    fn push_front(&self, urb: Urb) {
+        self.with(urb.ep, |queue| queue.push_front(urb));
+    }
+
+    /// Removes the first URB matching `f` from whichever queue it is in.
+    fn remove(&self, f: impl Fn(&Urb) -> bool) -> Option<Urb> {
+        Self::addresses().find_map(|ep_addr| self.with(ep_addr, |queue| {
+            queue.iter()
+                .position(&f)
+                .and_then(|index| queue.remove(index))
+        }))
+    }
+
+    fn clear(&self) {
+        for ep_addr in Self::addresses() {
+            self.with(ep_addr, |queue| queue.clear());
         }
     }
+
+    fn addresses() -> impl Iterator<Item = EndpointAddress> {
+        (0..NUM_ENDPOINTS as u8).flat_map(|number| {
+            vec![
+                EndpointAddress::from_parts(number, UsbDirection::Out),
+                EndpointAddress::from_parts(number, UsbDirection::In),
+            ]
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -566,8 +1684,94 @@ pub struct Urb {
     pub req_ep: EndpointAddress, // request endpoint, could be different for control URBs
     pub len: usize,
     pub control: Option<UrbControl>,
+    pub iso: Option<UrbIso>,
+    pub transfer_flags: u32,
     pub data: BytesMut,
+    pub status: ResponseStatus,
     pub internal: bool,
+    // Shared with the device the URB was submitted to, so that it can be unlinked while an
+    // endpoint holds it
+    pub state: Arc<UrbState>,
+    // The following are set on submit
+    pub generation: u32,
+    pub submitted: Instant,
+    pub span: Span,
+}
+
+impl Urb {
+    fn into_response(self) -> SubmitResponse {
+        let actual_length = match (&self.iso, self.req_ep.direction()) {
+            (Some(iso), _) => iso.packets.iter().map(|p| p.actual_length).sum(),
+            (None, UsbDirection::In) => self.data.len() as u32,
+            // OUT data is consumed as it is read
+            (None, UsbDirection::Out) if self.status == ResponseStatus::Ok => {
+                (self.len - self.data.len()) as u32
+            },
+            (None, UsbDirection::Out) => 0,
+        };
+
+        let (actual_start_frame, error_count, iso_packets) = match self.iso {
+            Some(iso) => (
+                // Echoed back, see UrbIso
+                iso.start_frame,
+                iso.packets.iter().filter(|p| p.status != 0).count() as u32,
+                iso.packets,
+            ),
+            None => (0, 0, Vec::new()),
+        };
+
+        SubmitResponse {
+            seqnum: self.seqnum,
+            devid: self.devid,
+            ep: self.req_ep,
+            status: self.status.code(),
+            actual_length,
+            actual_start_frame,
+            number_of_packets: iso_packets.len() as u32,
+            error_count,
+            setup: None,
+            data: if self.req_ep.direction() == UsbDirection::In { self.data } else { BytesMut::new() },
+            iso_packets,
+        }
+    }
+}
+
+/// Whether a URB has completed or been unlinked. Whichever happens first sticks.
+#[derive(Default, Debug)]
+pub struct UrbState(AtomicU8);
+
+impl UrbState {
+    const PENDING: u8 = 0;
+    const COMPLETE: u8 = 1;
+    const CANCELLED: u8 = 2;
+
+    fn is_pending(&self) -> bool {
+        self.0.load(SeqCst) == Self::PENDING
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.0.load(SeqCst) == Self::CANCELLED
+    }
+
+    /// Marks the URB complete. Returns false if it was cancelled first.
+    fn complete(&self) -> bool {
+        self.0.compare_exchange(Self::PENDING, Self::COMPLETE, SeqCst, SeqCst).is_ok()
+    }
+
+    /// Marks the URB cancelled. Returns false if it was completed first.
+    fn cancel(&self) -> bool {
+        self.0.compare_exchange(Self::PENDING, Self::CANCELLED, SeqCst, SeqCst).is_ok()
+    }
+}
+
+#[derive(Debug)]
+pub struct UrbIso {
+    // As requested by the host. Virtual devices have no frame counter, so this is also reported
+    // back as the actual start frame, even for URBs the host asked to be scheduled ASAP.
+    pub start_frame: u32,
+    pub packets: Vec<IsoPacketDescriptor>,
+    // Index of the packet to be transferred next
+    pub next_packet: usize,
 }
 
 #[derive(Debug)]
@@ -583,3 +1787,112 @@ pub enum ControlState {
     Status,
     Complete,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::CidrAllowlist;
+
+    fn registry_with_limit(max_imports: usize) -> Arc<Mutex<Registry>> {
+        let mut policy = CidrAllowlist::new();
+        policy.allow("0.0.0.0".parse().unwrap(), 0);
+        policy.set_max_imports(max_imports);
+
+        let mut registry = Registry::new();
+        registry.policy = Some(Arc::new(policy));
+
+        Arc::new(Mutex::new(registry))
+    }
+
+    fn session(registry: &Arc<Mutex<Registry>>, peer: &str) -> Session {
+        let (mut session, _) = Session::new(Arc::clone(registry));
+        session.peer_addr = Some(peer.parse().unwrap());
+        session
+    }
+
+    #[tokio::test]
+    async fn imports_are_released_on_close() {
+        let registry = registry_with_limit(1);
+
+        let mut first = session(&registry, "10.0.0.1:1000");
+
+        assert!(first.acquire_import("1-1"));
+        assert_eq!(first.counted_imports, 1);
+
+        // Another connection from the same address shares the limit
+        let mut second = session(&registry, "[::ffff:10.0.0.1]:1001");
+
+        assert!(!second.acquire_import("1-2"));
+        assert_eq!(second.counted_imports, 0);
+
+        first.close().await;
+
+        assert_eq!(first.counted_imports, 0);
+        assert!(registry.lock().unwrap().imports.is_empty());
+
+        assert!(second.acquire_import("1-2"));
+    }
+
+    #[tokio::test]
+    async fn closing_releases_only_own_imports() {
+        let registry = registry_with_limit(2);
+
+        let mut first = session(&registry, "10.0.0.1:1000");
+        let mut second = session(&registry, "10.0.0.1:1001");
+
+        assert!(first.acquire_import("1-1"));
+        assert!(second.acquire_import("1-2"));
+
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(registry.lock().unwrap().imports.get(&ip), Some(&2));
+
+        second.close().await;
+
+        assert_eq!(registry.lock().unwrap().imports.get(&ip), Some(&1));
+
+        first.release_imports(1);
+
+        assert!(registry.lock().unwrap().imports.is_empty());
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("usbip-usbd-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_unix_twice_fails() {
+        let path = socket_path("twice");
+
+        let first = Server::bind_unix(&path).await.unwrap();
+
+        match Server::bind_unix(&path).await {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::AddrInUse),
+            res => panic!("second bind did not fail: {:?}", res.map(|_| ())),
+        }
+
+        // The running server keeps its socket
+        assert!(path.exists());
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+
+        drop(first);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_unix_replaces_stale_socket() {
+        let path = socket_path("stale");
+
+        drop(Server::bind_unix(&path).await.unwrap());
+
+        // Nothing listens on the socket file any more
+        assert!(path.exists());
+
+        let _server = Server::bind_unix(&path).await.unwrap();
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::Duration;
+use usb_device::endpoint::EndpointType;
+
+/// Speed of a virtual device, as reported to the host.
+///
+/// The values are the same as in `enum usb_device_speed` in Linux, which is what USB/IP uses.
+/// There is no super speed, because usb-device can't build a device with the 512 byte control
+/// endpoint and the descriptors it requires.
+#[repr(u32)]
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub enum Speed {
+    Low = 1,
+    #[default]
+    Full = 2,
+    High = 3,
+    Wireless = 4,
+}
+
+impl Speed {
+    /// Returns true if `max_packet_size`, as in wMaxPacketSize, is allowed for an endpoint of type
+    /// `ep_type` at this speed. At high speed, bits 12..11 may specify additional transactions
+    /// per microframe for interrupt and isochronous endpoints.
+    pub fn is_valid_max_packet_size(self, ep_type: EndpointType, max_packet_size: u16) -> bool {
+        let size = max_packet_size & 0x07ff;
+        let mult = max_packet_size >> 11;
+
+        let high_bandwidth = self == Speed::High
+            && (ep_type == EndpointType::Interrupt || ep_type == EndpointType::Isochronous);
+
+        let max_mult = if high_bandwidth { 2 } else { 0 };
+
+        if mult > max_mult {
+            return false;
+        }
+
+        match (self, ep_type) {
+            (Speed::Low, EndpointType::Control) => size == 8,
+            (Speed::Low, EndpointType::Interrupt) => size <= 8,
+            (Speed::Low, _) => false, // no bulk or isochronous endpoints at low speed
+
+            (Speed::Full, EndpointType::Control) | (Speed::Full, EndpointType::Bulk) => {
+                [8, 16, 32, 64].contains(&size)
+            },
+            (Speed::Full, EndpointType::Interrupt) => size <= 64,
+            (Speed::Full, EndpointType::Isochronous) => size <= 1023,
+
+            // Wireless USB devices are treated as high speed
+            (Speed::High, EndpointType::Control) | (Speed::Wireless, EndpointType::Control) => size == 64,
+            (Speed::High, EndpointType::Bulk) | (Speed::Wireless, EndpointType::Bulk) => size == 512,
+            (Speed::High, _) | (Speed::Wireless, _) => size <= 1024,
+        }
+    }
+
+    /// Returns the polling interval of an interrupt or isochronous endpoint with the given
+    /// bInterval, or `None` if bInterval is out of range or the endpoint is not periodic.
+    ///
+    /// At low and full speed bInterval is in frames (1 ms), linearly for interrupt endpoints and
+    /// as an exponent for isochronous endpoints. At higher speeds it is always an exponent in
+    /// microframes (125 µs).
+    pub fn interval(self, ep_type: EndpointType, interval: u8) -> Option<Duration> {
+        const FRAME: Duration = Duration::from_millis(1);
+        const MICROFRAME: Duration = Duration::from_micros(125);
+
+        match (self, ep_type) {
+            (_, EndpointType::Control) | (_, EndpointType::Bulk) => None,
+
+            (Speed::Low, EndpointType::Interrupt) | (Speed::Full, EndpointType::Interrupt) => {
+                if interval >= 1 {
+                    Some(FRAME * u32::from(interval))
+                } else {
+                    None
+                }
+            },
+
+            (Speed::Low, EndpointType::Isochronous) => None,
+
+            (Speed::Full, EndpointType::Isochronous) => {
+                if (1..=16).contains(&interval) {
+                    Some(FRAME * (1 << (interval - 1)))
+                } else {
+                    None
+                }
+            },
+
+            (_, _) => {
+                if (1..=16).contains(&interval) {
+                    Some(MICROFRAME * (1 << (interval - 1)))
+                } else {
+                    None
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EndpointType::*;
+    use Speed::*;
+
+    #[test]
+    fn max_packet_sizes() {
+        // Speed, type, wMaxPacketSize, valid
+        let cases = [
+            (Low, Control, 8, true),
+            (Low, Control, 16, false),
+            (Low, Interrupt, 0, true),
+            (Low, Interrupt, 8, true),
+            (Low, Interrupt, 9, false),
+            (Low, Bulk, 8, false),
+            (Low, Isochronous, 8, false),
+
+            (Full, Control, 8, true),
+            (Full, Control, 64, true),
+            (Full, Control, 12, false),
+            (Full, Control, 128, false),
+            (Full, Bulk, 16, true),
+            (Full, Bulk, 64, true),
+            (Full, Bulk, 63, false),
+            (Full, Bulk, 512, false),
+            (Full, Interrupt, 64, true),
+            (Full, Interrupt, 65, false),
+            (Full, Isochronous, 1023, true),
+            (Full, Isochronous, 1024, false),
+            (Full, Interrupt, 0x0800 | 8, false), // no additional transactions
+
+            (High, Control, 64, true),
+            (High, Control, 8, false),
+            (High, Bulk, 512, true),
+            (High, Bulk, 64, false),
+            (High, Interrupt, 1024, true),
+            (High, Interrupt, 1025, false),
+            (High, Isochronous, 1024, true),
+            (High, Isochronous, 0x1000 | 1024, true), // two additional transactions
+            (High, Isochronous, 0x1800 | 1024, false),
+            (High, Interrupt, 0x0800 | 512, true),
+            (High, Bulk, 0x0800 | 512, false),
+
+            (Wireless, Control, 64, true),
+            (Wireless, Control, 512, false),
+            (Wireless, Bulk, 512, true),
+            (Wireless, Bulk, 1024, false),
+            (Wireless, Interrupt, 1024, true),
+            (Wireless, Interrupt, 0x0800 | 64, false), // only high speed allows high bandwidth
+        ];
+
+        for &(speed, ep_type, max_packet_size, valid) in &cases {
+            assert_eq!(
+                speed.is_valid_max_packet_size(ep_type, max_packet_size),
+                valid,
+                "{:?} {:?} {:#06x}",
+                speed,
+                ep_type,
+                max_packet_size);
+        }
+    }
+
+    #[test]
+    fn intervals() {
+        let ms = Duration::from_millis;
+        let us = Duration::from_micros;
+
+        // Speed, type, bInterval, interval
+        let cases = [
+            (Low, Control, 1, None),
+            (Full, Bulk, 1, None),
+            (High, Bulk, 1, None),
+
+            (Low, Interrupt, 0, None),
+            (Low, Interrupt, 1, Some(ms(1))),
+            (Low, Interrupt, 255, Some(ms(255))),
+            (Low, Isochronous, 1, None),
+
+            (Full, Interrupt, 0, None),
+            (Full, Interrupt, 1, Some(ms(1))),
+            (Full, Interrupt, 255, Some(ms(255))),
+            (Full, Isochronous, 0, None),
+            (Full, Isochronous, 1, Some(ms(1))),
+            (Full, Isochronous, 4, Some(ms(8))),
+            (Full, Isochronous, 16, Some(ms(32768))),
+            (Full, Isochronous, 17, None),
+
+            (High, Interrupt, 0, None),
+            (High, Interrupt, 1, Some(us(125))),
+            (High, Interrupt, 4, Some(ms(1))),
+            (High, Interrupt, 16, Some(us(125) * 32768)),
+            (High, Interrupt, 17, None),
+            (High, Isochronous, 1, Some(us(125))),
+            (High, Isochronous, 17, None),
+
+            (Wireless, Interrupt, 4, Some(ms(1))),
+            (Wireless, Isochronous, 0, None),
+        ];
+
+        for &(speed, ep_type, interval, expected) in &cases {
+            assert_eq!(
+                speed.interval(ep_type, interval),
+                expected,
+                "{:?} {:?} {}",
+                speed,
+                ep_type,
+                interval);
+        }
+    }
+
+    #[test]
+    fn default_is_full() {
+        assert_eq!(Speed::default(), Full);
+    }
+}
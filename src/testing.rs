@@ -0,0 +1,151 @@
+//! In-process loopback for testing classes without networking, root or `vhci-hcd`.
+//!
+//! ```no_run
+//! use usb_device::prelude::*;
+//! use usbd_serial::SerialPort;
+//! use usbip_usbd::{DeviceRunner, testing};
+//!
+//! # async fn example() {
+//! let (usbcore, poller, mut host) = testing::loopback();
+//!
+//! let mut serial = SerialPort::new();
+//!
+//! let usb_dev = UsbDeviceBuilder::new(usbcore, UsbVidPid(0x16c0, 0x27dd))
+//!     .build(&mut serial)
+//!     .expect("building device failed");
+//!
+//! tokio::spawn(DeviceRunner::new(poller, (usb_dev, serial)).run(|(usb_dev, serial)| {
+//!     usb_dev.poll(serial).ok();
+//! }));
+//!
+//! let desc = host.descriptors().await.unwrap();
+//! assert_eq!(desc.device.vendor_id, 0x16c0);
+//! # }
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::io::DuplexStream;
+use crate::{Error, ImportedDevice, Poller, Speed, UsbCore, UsbIpHost};
+use crate::descriptor::DeviceDescriptors;
+
+/// Bus ID of the loopback device.
+pub const BUS_ID: &str = "1-1";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Creates a full speed virtual device and a host connected to it in memory. Must be called from
+/// within a Tokio runtime.
+///
+/// The device must be polled, for instance with a [`DeviceRunner`](crate::DeviceRunner), for the
+/// host to be able to do anything.
+pub fn loopback() -> (UsbCore, Poller, TestHost) {
+    loopback_with_speed(Speed::Full)
+}
+
+/// Like [`loopback`], but the device runs at `speed`.
+pub fn loopback_with_speed(speed: Speed) -> (UsbCore, Poller, TestHost) {
+    let (usbcore, poller, stream) = crate::server::loopback(BUS_ID, speed)
+        .expect("attaching loopback device failed");
+
+    let host = TestHost {
+        stream: Some(stream),
+        device: None,
+        timeout: DEFAULT_TIMEOUT,
+    };
+
+    (usbcore, poller, host)
+}
+
+/// The host end of a loopback connection. The device is imported on first use.
+///
+/// Every method fails with an `Error::Io` of kind `TimedOut` if the device doesn't respond within
+/// the timeout, and with `Error::Transfer` if the transfer fails, for instance because the
+/// endpoint is stalled.
+pub struct TestHost {
+    stream: Option<DuplexStream>,
+    device: Option<ImportedDevice<DuplexStream>>,
+    timeout: Duration,
+}
+
+impl TestHost {
+    /// Sets the timeout for each operation. The default is one second.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    async fn device(&mut self) -> Result<&mut ImportedDevice<DuplexStream>, Error> {
+        if let Some(stream) = self.stream.take() {
+            let device = with_timeout(self.timeout, UsbIpHost::new(stream).import(BUS_ID)).await?;
+
+            self.device = Some(device);
+        }
+
+        // A failed import leaves neither
+        self.device.as_mut()
+            .ok_or_else(|| Error::Device("loopback device could not be imported".into()))
+    }
+
+    /// Reads all descriptors of the device.
+    pub async fn descriptors(&mut self) -> Result<DeviceDescriptors, Error> {
+        let timeout = self.timeout;
+        let device = self.device().await?;
+
+        with_timeout(timeout, device.descriptors()).await
+    }
+
+    pub async fn control_in(&mut self, request_type: u8, request: u8, value: u16, index: u16, length: u16)
+        -> Result<Bytes, Error>
+    {
+        let timeout = self.timeout;
+        let device = self.device().await?;
+
+        with_timeout(timeout, device.control_in(request_type, request, value, index, length)).await
+    }
+
+    pub async fn control_out(&mut self, request_type: u8, request: u8, value: u16, index: u16, data: &[u8])
+        -> Result<usize, Error>
+    {
+        let timeout = self.timeout;
+        let device = self.device().await?;
+
+        with_timeout(timeout, device.control_out(request_type, request, value, index, data)).await
+    }
+
+    pub async fn bulk_in(&mut self, ep: u8, len: usize) -> Result<Bytes, Error> {
+        let timeout = self.timeout;
+        let device = self.device().await?;
+
+        with_timeout(timeout, device.bulk_in(ep, len)).await
+    }
+
+    pub async fn bulk_out(&mut self, ep: u8, data: &[u8]) -> Result<usize, Error> {
+        let timeout = self.timeout;
+        let device = self.device().await?;
+
+        with_timeout(timeout, device.bulk_out(ep, data)).await
+    }
+
+    pub async fn interrupt_in(&mut self, ep: u8, len: usize) -> Result<Bytes, Error> {
+        let timeout = self.timeout;
+        let device = self.device().await?;
+
+        with_timeout(timeout, device.interrupt_in(ep, len)).await
+    }
+
+    pub async fn interrupt_out(&mut self, ep: u8, data: &[u8]) -> Result<usize, Error> {
+        let timeout = self.timeout;
+        let device = self.device().await?;
+
+        with_timeout(timeout, device.interrupt_out(ep, data)).await
+    }
+}
+
+async fn with_timeout<T>(timeout: Duration, f: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+    match tokio::time::timeout(timeout, f).await {
+        Ok(res) => res,
+        Err(_) => Err(Error::Io(io::ErrorKind::TimedOut.into())),
+    }
+}
@@ -0,0 +1,76 @@
+//! TLS for USB/IP connections. Requires the `tls` feature.
+//!
+//! Hosts are identified by their client certificates, and each certificate is allowed to list
+//! and import a fixed set of devices. The `vhci-hcd` driver can't speak TLS itself, so on Linux
+//! hosts it takes a local proxy such as `stunnel` or `socat` in front of `usbip attach`.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{Certificate, ServerConfig, Session as _};
+use tokio_rustls::server::TlsStream;
+
+pub use tokio_rustls::rustls;
+pub use tokio_rustls::webpki;
+
+// How long a client may take to complete the handshake, so that clients that connect and never
+// speak don't hold on to a task forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Server side TLS settings.
+#[derive(Clone)]
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+    // Bus IDs visible to each client, by the DER encoding of its certificate
+    bus_ids: HashMap<Vec<u8>, Arc<HashSet<String>>>,
+}
+
+impl TlsConfig {
+    /// Creates TLS settings from a rustls configuration. For clients to be identified, `config`
+    /// must require client certificates, for instance by using
+    /// [`AllowAnyAuthenticatedClient`](rustls::AllowAnyAuthenticatedClient) with the
+    /// certificates that are allowed to connect as roots.
+    ///
+    /// Clients can't see any devices until they are allowed to with [`allow`](Self::allow).
+    pub fn new(config: ServerConfig) -> Self {
+        TlsConfig {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            bus_ids: HashMap::new(),
+        }
+    }
+
+    /// Lets a client presenting `cert` list and import the devices with the bus IDs `bus_ids`,
+    /// in addition to any it was previously allowed.
+    pub fn allow<I>(&mut self, cert: &Certificate, bus_ids: I)
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let allowed = self.bus_ids.entry(cert.0.clone()).or_default();
+
+        Arc::make_mut(allowed).extend(bus_ids.into_iter().map(Into::into));
+    }
+
+    /// Performs the TLS handshake on `stream` and returns the bus IDs the client may access. Fails
+    /// if the handshake takes too long.
+    pub(crate) async fn accept<S>(&self, stream: S) -> io::Result<(TlsStream<S>, Arc<HashSet<String>>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+
+        // The end-entity certificate comes first
+        let bus_ids = stream.get_ref().1.get_peer_certificates()
+            .and_then(|certs| certs.into_iter().next())
+            .and_then(|cert| self.bus_ids.get(&cert.0))
+            .cloned()
+            .unwrap_or_default();
+
+        Ok((stream, bus_ids))
+    }
+}
@@ -1,7 +1,7 @@
 use usb_device::{
     Result, UsbError, UsbDirection,
     //class::UsbClass,
-    endpoint::{EndpointAddress, EndpointConfig},
+    endpoint::{EndpointAddress, EndpointConfig, EndpointType},
     usbcore::{self, PollResult},
 };
 use crate::server::CoreChannel;
@@ -37,7 +37,8 @@ impl usbcore::UsbCore for UsbCore {
     }
 
     fn reset(&mut self) -> Result<()> {
-        // TODO
+        self.channel.reset();
+
         Ok(())
     }
 
@@ -47,23 +48,17 @@ impl usbcore::UsbCore for UsbCore {
     }
 
     fn poll(&mut self) -> Result<PollResult> {
-        // TODO
-
-        Ok(PollResult::Data {
-            ep_out: 0xffff,
-            ep_in_complete: 0xffff,
-        })
+        Ok(self.channel.poll())
     }
 
     fn set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) -> Result<()> {
-        let _ = ep_addr;
-        let _ = stalled;
-        unimplemented!();
+        self.channel.set_stalled(ep_addr, stalled);
+
+        Ok(())
     }
 
     fn is_stalled(&mut self, ep_addr: EndpointAddress) -> Result<bool> {
-        let _ = ep_addr;
-        unimplemented!();
+        Ok(self.channel.is_stalled(ep_addr))
     }
 
     fn suspend(&mut self) -> Result<()> {
@@ -105,6 +100,31 @@ impl EndpointAllocator {
             return Err(UsbError::EndpointOverflow);
         }
 
+        let speed = self.channel.speed();
+
+        if !speed.is_valid_max_packet_size(config.ep_type(), config.max_packet_size()) {
+            tracing::warn!(
+                ?speed,
+                ep_type = ?config.ep_type(),
+                max_packet_size = config.max_packet_size(),
+                "invalid max packet size for endpoint");
+
+            return Err(UsbError::Unsupported);
+        }
+
+        let periodic = config.ep_type() == EndpointType::Interrupt
+            || config.ep_type() == EndpointType::Isochronous;
+
+        if periodic && speed.interval(config.ep_type(), config.interval()).is_none() {
+            tracing::warn!(
+                ?speed,
+                ep_type = ?config.ep_type(),
+                interval = config.interval(),
+                "invalid interval for endpoint");
+
+            return Err(UsbError::Unsupported);
+        }
+
         match direction {
             UsbDirection::Out => {
                 if self.out_taken & (1 << number) != 0 {
@@ -133,7 +153,10 @@ impl EndpointAllocator {
             self.next_endpoint_number += 1;
         }
 
-        Ok((EndpointAddress::from_parts(number, direction), config.max_packet_size().into()))
+        // Additional transactions per microframe don't change the size of a single packet
+        let max_packet_size = usize::from(config.max_packet_size() & 0x07ff);
+
+        Ok((EndpointAddress::from_parts(number, direction), max_packet_size))
     }
 }
 
@@ -0,0 +1,37 @@
+#!/bin/sh
+# Regenerates the certificates used by tests/tls.rs: a test CA, a server certificate for
+# "localhost" and two client certificates.
+
+set -e
+
+cd "$(dirname "$0")"
+
+key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+}
+
+key ca
+openssl req -x509 -new -key ca.key -days 36500 -subj "/CN=usbip-usbd test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" \
+    -addext "keyUsage=critical,keyCertSign" \
+    -out ca.pem
+
+sign() {
+    name=$1
+    cn=$2
+    ext=$3
+
+    key "$name"
+    openssl req -new -key "$name.key" -subj "/CN=$cn" -out "$name.csr"
+    printf '%s\n' "basicConstraints=critical,CA:FALSE" "keyUsage=critical,digitalSignature" "$ext" > "$name.ext"
+    openssl x509 -req -in "$name.csr" -CA ca.pem -CAkey ca.key -set_serial "0x$(openssl rand -hex 8)" \
+        -days 36500 -extfile "$name.ext" -out "$name.pem"
+    rm "$name.csr" "$name.ext"
+}
+
+sign server localhost "subjectAltName=DNS:localhost
+extendedKeyUsage=serverAuth"
+sign client1 client1 "extendedKeyUsage=clientAuth"
+sign client2 client2 "extendedKeyUsage=clientAuth"
+
+rm ca.key
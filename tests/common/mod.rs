@@ -0,0 +1,31 @@
+use usb_device::prelude::*;
+use usbd_serial::SerialPort;
+use usbip_usbd::{DeviceRunner, Poller, UsbCore};
+
+/// Builds a serial port device on `usbcore` and polls it in the background, echoing back
+/// everything written to it.
+pub fn run_echo(usbcore: UsbCore, poller: Poller) {
+    let mut serial = SerialPort::new();
+
+    let usb_dev = UsbDeviceBuilder::new(usbcore, UsbVidPid(0x16c0, 0x27dd))
+        .product("Echo")
+        .build(&mut serial)
+        .expect("building device failed");
+
+    tokio::spawn(DeviceRunner::new(poller, (usb_dev, serial)).run(|(usb_dev, serial)| {
+        if usb_dev.poll(serial).is_err() {
+            return;
+        }
+
+        let mut buf = [0u8; 64];
+
+        loop {
+            match serial.read(&mut buf) {
+                Ok(count) if count > 0 => {
+                    serial.write(&buf[..count]).ok();
+                },
+                _ => break,
+            }
+        }
+    }));
+}
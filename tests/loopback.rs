@@ -0,0 +1,209 @@
+use std::io;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::time::timeout;
+use usb_device::control::Request;
+use usbip_usbd::{Error, ImportedDevice, Server, UsbIpHost};
+use usbip_usbd::descriptor::DeviceDescriptors;
+use usbip_usbd::testing::{self, TestHost};
+
+mod common;
+use common::run_echo;
+
+/// Starts a serial port device that echoes back everything written to it.
+fn echo_device() -> TestHost {
+    let (usbcore, poller, host) = testing::loopback();
+
+    run_echo(usbcore, poller);
+
+    host
+}
+
+/// Serves an echo device on a server, and returns the host end of a connection that has imported
+/// it.
+async fn import_echo() -> ImportedDevice<DuplexStream> {
+    let mut server = Server::new();
+
+    let (usbcore, poller) = server.attach("1-1").unwrap();
+    run_echo(usbcore, poller);
+
+    let (host_stream, device_stream) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(server.client(device_stream, "host").run());
+
+    timeout(Duration::from_secs(1), UsbIpHost::new(host_stream).import("1-1"))
+        .await
+        .expect("import timed out")
+        .unwrap()
+}
+
+/// Returns the bulk OUT and IN endpoint numbers of the device.
+fn bulk_endpoints(desc: &DeviceDescriptors) -> (u8, u8) {
+    let bulk = desc.configurations[0].interfaces.iter()
+        .flat_map(|iface| &iface.alt_settings)
+        .flat_map(|alt| &alt.endpoints)
+        .filter(|ep| ep.attributes & 0x03 == 0x02)
+        .map(|ep| ep.address)
+        .collect::<Vec<_>>();
+
+    let ep_out = bulk.iter().find(|&&a| a & 0x80 == 0).expect("no bulk OUT endpoint");
+    let ep_in = bulk.iter().find(|&&a| a & 0x80 != 0).expect("no bulk IN endpoint");
+
+    (ep_out & 0x0f, ep_in & 0x0f)
+}
+
+fn is_timeout(res: &Result<impl std::fmt::Debug, Error>) -> bool {
+    match res {
+        Err(Error::Io(err)) => err.kind() == io::ErrorKind::TimedOut,
+        _ => false,
+    }
+}
+
+#[tokio::test]
+async fn get_descriptor_right_after_import() {
+    let mut host = echo_device();
+
+    // The import resets the device, and the first SETUP must not get stuck behind the reset
+    let desc = host.control_in(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 18).await.unwrap();
+
+    assert_eq!(desc.len(), 18);
+    assert_eq!(desc[1], 0x01);
+}
+
+#[tokio::test]
+async fn get_descriptor() {
+    let mut host = echo_device();
+
+    let desc = host.descriptors().await.unwrap();
+
+    assert_eq!(desc.device.vendor_id, 0x16c0);
+    assert_eq!(desc.device.product_id, 0x27dd);
+    assert_eq!(desc.string(desc.device.product_index), Some("Echo"));
+}
+
+#[tokio::test]
+async fn rejected_request_stalls() {
+    let mut host = echo_device();
+
+    // No class handles vendor requests, so the device rejects it
+    let res = host.control_in(0xc0, 0x42, 0, 0, 8).await;
+
+    assert!(matches!(res, Err(Error::Transfer(-32))), "unexpected result: {:?}", res);
+
+    // A stalled control endpoint recovers on the next SETUP
+    host.control_in(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 18).await.unwrap();
+}
+
+#[tokio::test]
+async fn bulk_round_trip() {
+    let mut host = echo_device();
+
+    let (ep_out, ep_in) = bulk_endpoints(&host.descriptors().await.unwrap());
+
+    assert_eq!(host.bulk_out(ep_out, b"hello").await.unwrap(), 5);
+    assert_eq!(&host.bulk_in(ep_in, 64).await.unwrap()[..], b"hello");
+}
+
+#[tokio::test]
+async fn unpolled_device_times_out() {
+    // Nothing ever polls the device
+    let (_usbcore, _poller, mut host) = testing::loopback();
+
+    host.set_timeout(Duration::from_millis(100));
+
+    let res = host.control_in(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 18).await;
+
+    assert!(is_timeout(&res), "unexpected result: {:?}", res);
+}
+
+#[tokio::test]
+async fn idle_endpoint_times_out() {
+    let mut host = echo_device();
+
+    let (_, ep_in) = bulk_endpoints(&host.descriptors().await.unwrap());
+
+    host.set_timeout(Duration::from_millis(100));
+
+    let res = host.bulk_in(ep_in, 64).await;
+
+    assert!(is_timeout(&res), "unexpected result: {:?}", res);
+}
+
+#[tokio::test]
+async fn import_after_dropped_connection() {
+    let mut server = Server::new();
+
+    let (usbcore, poller) = server.attach("1-1").unwrap();
+    run_echo(usbcore, poller);
+
+    let (host_stream, device_stream) = tokio::io::duplex(64 * 1024);
+    let mut first = Box::pin(server.client(device_stream, "first").run());
+
+    // Serve the first connection only until the import completes, then drop it without closing
+    let import = UsbIpHost::new(host_stream).import("1-1");
+
+    let _imported = tokio::select! {
+        res = import => res.unwrap(),
+        res = &mut first => panic!("connection ended early: {:?}", res),
+    };
+
+    drop(first);
+
+    let (host_stream, device_stream) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(server.client(device_stream, "second").run());
+
+    let mut device = timeout(Duration::from_secs(1), UsbIpHost::new(host_stream).import("1-1"))
+        .await
+        .expect("import timed out")
+        .expect("device still imported by the dropped connection");
+
+    let desc = timeout(Duration::from_secs(1), device.control_in(0x80, Request::GET_DESCRIPTOR, 0x0100, 0, 18))
+        .await
+        .expect("GET_DESCRIPTOR timed out")
+        .unwrap();
+
+    assert_eq!(desc.len(), 18);
+}
+
+#[tokio::test]
+async fn unlink_pending_urb() {
+    let mut device = import_echo().await;
+
+    let (ep_out, ep_in) = bulk_endpoints(&device.descriptors().await.unwrap());
+
+    // Nothing has been written yet, so the read stays pending
+    let seqnum = device.submit_in(ep_in, 64).await.unwrap();
+
+    assert!(device.unlink(seqnum).await.unwrap(), "pending URB was not unlinked");
+
+    // The data goes to the next read instead of the unlinked one
+    device.bulk_out(ep_out, b"hello").await.unwrap();
+
+    let data = timeout(Duration::from_secs(1), device.bulk_in(ep_in, 64))
+        .await
+        .expect("read after unlink timed out")
+        .unwrap();
+
+    assert_eq!(&data[..], b"hello");
+
+    // No RET_SUBMIT ever arrives for the unlinked URB
+    assert!(timeout(Duration::from_millis(100), device.wait(seqnum)).await.is_err());
+}
+
+#[tokio::test]
+async fn unlink_completed_urb() {
+    let mut device = import_echo().await;
+
+    let (ep_out, _) = bulk_endpoints(&device.descriptors().await.unwrap());
+
+    let seqnum = device.submit_out(ep_out, b"hello").await.unwrap();
+
+    let completion = timeout(Duration::from_secs(1), device.wait(seqnum))
+        .await
+        .expect("write timed out")
+        .unwrap();
+
+    assert_eq!(completion.status, 0);
+
+    // Unlinking a URB that has already completed answers with status 0
+    assert!(!device.unlink(seqnum).await.unwrap(), "completed URB was unlinked");
+}
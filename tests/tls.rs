@@ -0,0 +1,132 @@
+#![cfg(feature = "tls")]
+
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use usbip_usbd::{Server, UsbIpHost};
+use usbip_usbd::tls::TlsConfig;
+use usbip_usbd::tls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig,
+};
+use usbip_usbd::tls::rustls::internal::pemfile;
+
+mod common;
+use common::run_echo;
+
+// Generated by tests/certs/generate.sh
+const CA: &[u8] = include_bytes!("certs/ca.pem");
+const SERVER_CERT: &[u8] = include_bytes!("certs/server.pem");
+const SERVER_KEY: &[u8] = include_bytes!("certs/server.key");
+const CLIENT1_CERT: &[u8] = include_bytes!("certs/client1.pem");
+const CLIENT1_KEY: &[u8] = include_bytes!("certs/client1.key");
+const CLIENT2_CERT: &[u8] = include_bytes!("certs/client2.pem");
+
+fn cert(pem: &[u8]) -> Certificate {
+    pemfile::certs(&mut BufReader::new(pem)).unwrap().remove(0)
+}
+
+fn key(pem: &[u8]) -> PrivateKey {
+    pemfile::pkcs8_private_keys(&mut BufReader::new(pem)).unwrap().remove(0)
+}
+
+fn roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add(&cert(CA)).unwrap();
+    roots
+}
+
+/// Starts a TLS server exporting the devices 1-1 and 1-2. The first client certificate may only
+/// access 1-1, and the second one only 1-2.
+async fn start_server() -> SocketAddr {
+    let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots()));
+    config.set_single_cert(vec![cert(SERVER_CERT)], key(SERVER_KEY)).unwrap();
+
+    let mut tls = TlsConfig::new(config);
+    tls.allow(&cert(CLIENT1_CERT), vec!["1-1"]);
+    tls.allow(&cert(CLIENT2_CERT), vec!["1-2"]);
+
+    let mut server = Server::bind("127.0.0.1:0").await.unwrap();
+    server.set_tls(tls);
+
+    for bus_id in &["1-1", "1-2"] {
+        let (usbcore, poller) = server.attach(bus_id).unwrap();
+        run_echo(usbcore, poller);
+    }
+
+    let addr = server.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok(client) = server.accept().await {
+            tokio::spawn(client.run());
+        }
+    });
+
+    addr
+}
+
+fn client_config(cert_and_key: Option<(&[u8], &[u8])>) -> Arc<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.root_store = roots();
+
+    if let Some((cert_pem, key_pem)) = cert_and_key {
+        config.set_single_client_cert(vec![cert(cert_pem)], key(key_pem)).unwrap();
+    }
+
+    Arc::new(config)
+}
+
+#[tokio::test]
+async fn client_sees_only_allowed_devices() {
+    let addr = start_server().await;
+    let config = client_config(Some((CLIENT1_CERT, CLIENT1_KEY)));
+
+    let mut host = UsbIpHost::connect_tls(addr, Arc::clone(&config), "localhost").await.unwrap();
+
+    let devices = timeout(Duration::from_secs(5), host.list_devices())
+        .await
+        .expect("device list timed out")
+        .unwrap();
+
+    let bus_ids = devices.iter().map(|d| d.device.busid.as_str()).collect::<Vec<_>>();
+    assert_eq!(bus_ids, ["1-1"]);
+
+    let host = UsbIpHost::connect_tls(addr, Arc::clone(&config), "localhost").await.unwrap();
+    let mut device = timeout(Duration::from_secs(5), host.import("1-1"))
+        .await
+        .expect("import timed out")
+        .unwrap();
+
+    assert_eq!(device.info().busid, "1-1");
+    assert_eq!(device.descriptors().await.unwrap().device.vendor_id, 0x16c0);
+
+    // The other device looks like it doesn't exist
+    let host = UsbIpHost::connect_tls(addr, Arc::clone(&config), "localhost").await.unwrap();
+    let res = timeout(Duration::from_secs(5), host.import("1-2"))
+        .await
+        .expect("import timed out");
+
+    assert!(res.is_err(), "imported a device the certificate is not allowed");
+}
+
+#[tokio::test]
+async fn client_without_certificate_sees_nothing() {
+    let addr = start_server().await;
+    let config = client_config(None);
+
+    // Depending on the TLS version, the server rejects the client either during the handshake or
+    // on the first read
+    let mut host = match UsbIpHost::connect_tls(addr, config, "localhost").await {
+        Ok(host) => host,
+        Err(_) => return,
+    };
+
+    let res = timeout(Duration::from_secs(5), host.list_devices())
+        .await
+        .expect("device list timed out");
+
+    if let Ok(devices) = res {
+        assert!(devices.is_empty(), "client without a certificate sees {} devices", devices.len());
+    }
+}